@@ -0,0 +1 @@
+DROP TABLE timestamp_conversion;
@@ -0,0 +1,12 @@
+-- 从这个版本开始，所有时间都按UTC保存。
+-- 旧数据需要用`backend-demo migrate-timestamps <timezone>`转换，转换完成后会在这里留下记录；
+-- 空数据库不需要转换，直接记为已完成。
+CREATE TABLE timestamp_conversion (
+    id INTEGER NOT NULL PRIMARY KEY,
+    source_timezone TEXT NOT NULL,
+    converted_at DATETIME NOT NULL
+);
+
+INSERT INTO timestamp_conversion (source_timezone, converted_at)
+SELECT 'UTC', CURRENT_TIMESTAMP
+WHERE NOT EXISTS (SELECT 1 FROM user) AND NOT EXISTS (SELECT 1 FROM message);
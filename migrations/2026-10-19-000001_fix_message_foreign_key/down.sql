@@ -0,0 +1,15 @@
+CREATE TABLE message_old (
+    id INTEGER NOT NULL PRIMARY KEY,
+    user INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    content TEXT NOT NULL,
+    pub_date DATETIME NOT NULL,
+    FOREIGN KEY(user) REFERENCES user(user)
+);
+
+INSERT INTO message_old (id, user, title, content, pub_date)
+SELECT id, user, title, content, pub_date FROM message;
+
+DROP TABLE message;
+
+ALTER TABLE message_old RENAME TO message;
@@ -0,0 +1,17 @@
+-- 原来的外键指向不存在的`user(user)`列，打开外键检查后插入留言会报"foreign key mismatch"。
+-- SQLite不能修改外键，只能重建表。
+CREATE TABLE message_new (
+    id INTEGER NOT NULL PRIMARY KEY,
+    user INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    content TEXT NOT NULL,
+    pub_date DATETIME NOT NULL,
+    FOREIGN KEY(user) REFERENCES user(id)
+);
+
+INSERT INTO message_new (id, user, title, content, pub_date)
+SELECT id, user, title, content, pub_date FROM message;
+
+DROP TABLE message;
+
+ALTER TABLE message_new RENAME TO message;
@@ -0,0 +1,4 @@
+DROP TRIGGER message_deleted;
+DROP TRIGGER message_updated;
+DROP TRIGGER message_inserted;
+DROP TABLE board_revision;
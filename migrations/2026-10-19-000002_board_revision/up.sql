@@ -0,0 +1,25 @@
+-- 留言每次增删改都让revision加一并记下时间，列表接口据此生成ETag和Last-Modified。
+-- 以后重建message表时要重新创建这些触发器。
+CREATE TABLE board_revision (
+    id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+    revision INTEGER NOT NULL,
+    updated_at DATETIME NOT NULL
+);
+
+INSERT INTO board_revision (id, revision, updated_at)
+VALUES (1, 0, strftime('%Y-%m-%d %H:%M:%f', 'now'));
+
+CREATE TRIGGER message_inserted AFTER INSERT ON message
+BEGIN
+    UPDATE board_revision SET revision = revision + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = 1;
+END;
+
+CREATE TRIGGER message_updated AFTER UPDATE ON message
+BEGIN
+    UPDATE board_revision SET revision = revision + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = 1;
+END;
+
+CREATE TRIGGER message_deleted AFTER DELETE ON message
+BEGIN
+    UPDATE board_revision SET revision = revision + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = 1;
+END;
@@ -0,0 +1,26 @@
+-- 管理员登记的webhook。`events`是逗号分隔的事件名
+CREATE TABLE webhook (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
+
+-- 待投递和已投递的事件，同时也是投递记录。status为pending、delivered或failed
+CREATE TABLE webhook_delivery (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    webhook INTEGER NOT NULL REFERENCES webhook (id),
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at DATETIME NOT NULL,
+    last_status INTEGER,
+    last_error TEXT,
+    created_at DATETIME NOT NULL,
+    delivered_at DATETIME
+);
+
+CREATE INDEX webhook_delivery_due ON webhook_delivery (status, next_attempt_at);
+CREATE INDEX webhook_delivery_webhook ON webhook_delivery (webhook, id);
@@ -0,0 +1,86 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web::{self, Bytes}};
+use chrono::Utc;
+use qstring::QString;
+use serde_json::json;
+use crate::Pool;
+use crate::backup;
+use crate::config::{BackupOptions, ContentLimits, RetentionOptions};
+use crate::error::ApiError;
+use crate::maintenance;
+use crate::retention;
+use crate::transfer::{self, ExportCursor};
+
+#[post("/api/admin/backup")]
+pub async fn create_backup(options: web::Data<BackupOptions>) -> Result<HttpResponse, ApiError> {
+    let options = options.get_ref().clone();
+    let path = web::block(move || backup::create_snapshot(&options)).await?;
+    Ok(HttpResponse::Created().json(json!({ "path": path.display().to_string() })))
+}
+
+#[get("/api/admin/export")]
+pub async fn export_board(pool: web::Data<Pool>) -> impl Responder {
+    let stream = futures::stream::unfold(ExportCursor::start(), move |cursor| {
+        let pool = pool.clone();
+        async move {
+            if let ExportCursor::Done = cursor {
+                return None;
+            }
+            let chunk = web::block(move || {
+                let db_connection = pool.get().map_err(|e| e.to_string())?;
+                transfer::export_chunk(&db_connection, cursor).map_err(|e| e.to_string())
+            }).await;
+            match chunk {
+                Ok((_, ExportCursor::Done)) => None,
+                Ok((text, next)) => Some((Ok(Bytes::from(text)), next)),
+                Err(e) => Some((Err(e), ExportCursor::Done)),
+            }
+        }
+    }); //一页一页地读数据库，边读边发送，不把整个留言板放进内存
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(Box::pin(stream))
+}
+
+/// 请求体是JSONL，带上`?dry_run=true`时只检查不写入
+pub async fn import_board(
+    body: Bytes,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    limits: web::Data<ContentLimits>,
+) -> Result<HttpResponse, ApiError> {
+    let query_string = QString::from(request.query_string());
+    let dry_run = matches!(query_string.get("dry_run"), Some("true") | Some("1"));
+    let report = web::block(move || {
+        let db_connection = pool.get().map_err(|e| e.to_string())?;
+        transfer::import_board(&db_connection, &body[..], dry_run, &limits).map_err(|e| e.to_string())
+    }).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// 立即按保留策略清理一次，返回删除了多少条留言
+#[post("/api/admin/retention")]
+pub async fn run_retention(pool: web::Data<Pool>, options: web::Data<RetentionOptions>) -> Result<HttpResponse, ApiError> {
+    if !options.is_enabled() {
+        return Err(ApiError::Conflict(String::from("No retention policy is configured")));
+    }
+    let options = options.get_ref().clone();
+    let report = web::block(move || {
+        let db_connection = pool.get().map_err(|e| e.to_string())?;
+        retention::apply_retention(&db_connection, &options, Utc::now().naive_utc()).map_err(|e| e.to_string())
+    }).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// 检查数据库的健康状况。`?fix=true`时修复作者不存在的留言和同名用户，
+/// `?vacuum=true`时再执行`VACUUM`和`wal_checkpoint(TRUNCATE)`
+#[post("/api/admin/maintenance")]
+pub async fn run_maintenance(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    let query_string = QString::from(request.query_string());
+    let apply_fixes = matches!(query_string.get("fix"), Some("true") | Some("1"));
+    let compact = matches!(query_string.get("vacuum"), Some("true") | Some("1"));
+    let report = web::block(move || {
+        let db_connection = pool.get().map_err(|e| e.to_string())?;
+        maintenance::run(&db_connection, apply_fixes, compact).map_err(|e| e.to_string())
+    }).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
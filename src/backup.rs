@@ -0,0 +1,138 @@
+use std::{fmt, fs, path::{Path, PathBuf}, time::Duration};
+use actix_web::{rt, web};
+use chrono::Utc;
+use rusqlite::{Connection, DatabaseName, OpenFlags, backup::Backup};
+use crate::config::BackupOptions;
+use crate::jobs::Jobs;
+
+const BACKUP_PREFIX: &str = "backend-";
+const BACKUP_SUFFIX: &str = ".db";
+
+#[derive(Debug)]
+pub enum BackupError {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            BackupError::Io(e) => write!(f, "I/O error: {}", e),
+            BackupError::Invalid(reason) => write!(f, "Invalid backup: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(e: rusqlite::Error) -> Self {
+        BackupError::Sqlite(e)
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+/// 用SQLite的在线备份API把`database_url`复制到`destination`。
+/// 备份过程中服务器仍可正常读写（WAL模式下），得到的是一个一致的快照。
+pub fn backup_database(database_url: &str, destination: &Path) -> Result<(), BackupError> {
+    let partial = destination.with_extension("partial");
+    let source = Connection::open_with_flags(database_url, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    {
+        let mut target = Connection::open(&partial)?;
+        let backup = Backup::new(&source, &mut target)?;
+        backup.run_to_completion(100, Duration::from_millis(10), None)?;
+    } //先写到临时文件，完成后再改名，避免留下半个备份
+    fs::rename(&partial, destination)?;
+    Ok(())
+}
+
+/// 检查备份文件是否完好，并且包含本程序需要的表
+pub fn validate_backup(path: &Path) -> Result<(), BackupError> {
+    if !path.is_file() {
+        return Err(BackupError::Invalid(format!("{} is not a file", path.display())));
+    }
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let integrity: String = connection.query_row("PRAGMA integrity_check", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(BackupError::Invalid(format!("integrity check failed: {}", integrity)));
+    }
+    for table in &["user", "message"] {
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            &[table],
+            |row| row.get(0),
+        )?;
+        if count == 0 {
+            return Err(BackupError::Invalid(format!("table '{}' is missing", table)));
+        }
+    }
+    Ok(())
+}
+
+/// 校验备份后，通过在线备份API把它整体写回`database_url`。
+/// 应当在服务器停止时执行。
+pub fn restore_database(database_url: &str, backup: &Path) -> Result<(), BackupError> {
+    validate_backup(backup)?;
+    let mut target = Connection::open(database_url)?;
+    target.restore(DatabaseName::Main, backup, None::<fn(rusqlite::backup::Progress)>)?;
+    Ok(())
+}
+
+/// 在`options.directory`下创建一个带时间戳的备份，并删除超出保留数量的旧备份
+pub fn create_snapshot(options: &BackupOptions) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(&options.directory)?;
+    let file_name = format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        Utc::now().format("%Y%m%d-%H%M%S%.3f"),
+        BACKUP_SUFFIX
+    );
+    let destination = options.directory.join(file_name);
+    backup_database(&options.database_url, &destination)?;
+    rotate_backups(&options.directory, options.keep)?;
+    Ok(destination)
+}
+
+fn rotate_backups(directory: &Path, keep: usize) -> Result<(), BackupError> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX))
+                .unwrap_or(false)
+        })
+        .collect();
+    backups.sort(); //文件名中的时间戳可以直接按字典序排序
+    if backups.len() > keep {
+        for old in &backups[..backups.len() - keep] {
+            fs::remove_file(old)?;
+        }
+    }
+    Ok(())
+}
+
+/// 如果设置了备份间隔，就在actix运行时里启动一个定时备份任务
+pub fn spawn_periodic_backups(jobs: &mut Jobs, options: BackupOptions) {
+    let period = match options.interval {
+        Some(period) => period,
+        None => return,
+    };
+    jobs.spawn("backup", rt::time::Instant::now() + period, period, move || {
+        let options = options.clone();
+        async move {
+            match web::block(move || create_snapshot(&options)).await {
+                Ok(path) => tracing::info!(path = %path.display(), "periodic backup written"),
+                Err(e) => tracing::error!(error = %e, "periodic backup failed"),
+            }
+        }
+    });
+}
@@ -0,0 +1,139 @@
+use chrono::Utc;
+use diesel::{RunQueryDsl, insert_into, prelude::*};
+use crate::models::*;
+
+/// 当前最大的用户id加1，没有用户时为1
+pub fn next_user_id(db_connection: &SqliteConnection) -> QueryResult<i32> {
+    use crate::schema::user::dsl::*;
+    user.select(diesel::dsl::max(id))
+        .first::<Option<i32>>(db_connection)
+        .map(|last| last.map_or(1, |last| last + 1))
+}
+
+/// 从数据库中获取最新的id，加1作为新留言的id
+pub fn next_message_id(db_connection: &SqliteConnection) -> QueryResult<i32> {
+    use crate::schema::message::dsl::*;
+    message.select(diesel::dsl::max(id))
+        .first::<Option<i32>>(db_connection)
+        .map(|last| last.map_or(1, |last| last + 1))
+}
+
+pub fn find_user(db_connection: &SqliteConnection, user_name: &str) -> QueryResult<Option<PostUser>> {
+    use crate::schema::user::dsl::*;
+    user.filter(name.eq(user_name)).first::<PostUser>(db_connection).optional()
+}
+
+/// 新id是"最大id加1"，取id和插入要在同一个写事务里完成，否则并发的请求会拿到同一个id。
+/// 这里不自己开事务，调用方要在`immediate_transaction`里调用
+pub fn insert_user(db_connection: &SqliteConnection, user_name: &str) -> QueryResult<PostUser> {
+    use crate::schema::user::dsl::*;
+    let new_user = PostUser {
+        id: next_user_id(db_connection)?,
+        name: String::from(user_name),
+        register_date: Utc::now().naive_utc(),
+    };
+    insert_into(user).values(&new_user).execute(db_connection)?;
+    Ok(new_user)
+}
+
+/// 保存一条留言。和`insert_user`一样要在`immediate_transaction`里调用
+pub fn insert_message(db_connection: &SqliteConnection, author: i32, title: &str, content: &str) -> QueryResult<PostMessage> {
+    use crate::schema::message::dsl::message;
+    let new_message = PostMessage {
+        id: next_message_id(db_connection)?,
+        user: author,
+        title: String::from(title),
+        content: String::from(content),
+        pub_date: Utc::now().naive_utc(),
+    };
+    insert_into(message).values(&new_message).execute(db_connection)?;
+    Ok(new_message)
+}
+
+/// 删除所有留言，返回删除的条数
+pub fn clear_messages(db_connection: &SqliteConnection) -> QueryResult<usize> {
+    use crate::schema::message::dsl::*;
+    diesel::delete(message).execute(db_connection)
+}
+
+pub fn list_users(db_connection: &SqliteConnection) -> QueryResult<Vec<(PostUser, i64)>> {
+    use crate::schema::{message, user};
+    let users = user::table.order(user::id).load::<PostUser>(db_connection)?;
+    users
+        .into_iter()
+        .map(|item| {
+            let count = message::table
+                .filter(message::user.eq(item.id))
+                .count()
+                .get_result::<i64>(db_connection)?;
+            Ok((item, count))
+        })
+        .collect()
+}
+
+/// 删除用户。用户还有留言时，只有`with_messages`为真才会把留言一起删除，否则返回`Ok(None)`
+pub fn delete_user(db_connection: &SqliteConnection, target: &PostUser, with_messages: bool) -> QueryResult<Option<usize>> {
+    use crate::schema::{message, user};
+    db_connection.transaction(|| {
+        let messages = message::table.filter(message::user.eq(target.id));
+        let count = messages.count().get_result::<i64>(db_connection)?;
+        if count > 0 && !with_messages {
+            return Ok(None);
+        }
+        let removed = diesel::delete(messages).execute(db_connection)?;
+        diesel::delete(user::table.find(target.id)).execute(db_connection)?;
+        Ok(Some(removed))
+    })
+}
+
+pub fn rename_user(db_connection: &SqliteConnection, target: &PostUser, new_name: &str) -> QueryResult<usize> {
+    use crate::schema::user::dsl::*;
+    diesel::update(user.find(target.id)).set(name.eq(new_name)).execute(db_connection)
+}
+
+/// 按id顺序列出留言及其作者名，作者不存在时名字为空
+pub fn list_messages(
+    db_connection: &SqliteConnection,
+    author: Option<i32>,
+    limit: i64,
+    offset: i64,
+) -> QueryResult<Vec<(PostMessage, Option<String>)>> {
+    use crate::schema::{message, user};
+    let mut query = message::table
+        .left_join(user::table.on(user::id.eq(message::user)))
+        .select((message::all_columns, user::name.nullable()))
+        .order(message::id)
+        .limit(limit)
+        .offset(offset)
+        .into_boxed();
+    if let Some(author) = author {
+        query = query.filter(message::user.eq(author));
+    }
+    query.load(db_connection)
+}
+
+/// 按发表时间从新到旧列出留言及其作者名，订阅源用
+pub fn latest_messages(
+    db_connection: &SqliteConnection,
+    author: Option<i32>,
+    limit: i64,
+    offset: i64,
+) -> QueryResult<Vec<(PostMessage, Option<String>)>> {
+    use crate::schema::{message, user};
+    let mut query = message::table
+        .left_join(user::table.on(user::id.eq(message::user)))
+        .select((message::all_columns, user::name.nullable()))
+        .order((message::pub_date.desc(), message::id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .into_boxed();
+    if let Some(author) = author {
+        query = query.filter(message::user.eq(author));
+    }
+    query.load(db_connection)
+}
+
+pub fn delete_message(db_connection: &SqliteConnection, message_id: i32) -> QueryResult<usize> {
+    use crate::schema::message::dsl::*;
+    diesel::delete(message.find(message_id)).execute(db_connection)
+}
@@ -0,0 +1,315 @@
+use diesel::connection::SimpleConnection;
+use std::{fs::File, io::{BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+use clap::{Parser, Subcommand};
+use crate::Pool;
+use crate::backup;
+use crate::board;
+use crate::config::{Config, ConfigOverrides, ContentLimits};
+use crate::error::ApiError;
+use crate::maintenance;
+use crate::operations;
+use crate::seed;
+use crate::timezone;
+use crate::transfer;
+
+#[derive(Debug, Parser)]
+#[command(name = "backend-demo", about = "Message board backend")]
+pub struct Cli {
+    /// TOML config file (defaults to $CONFIG_FILE, then ./backend.toml if present)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the HTTP server (the default)
+    Serve,
+    /// Take a consistent snapshot of the database
+    Backup { path: PathBuf },
+    /// Validate a backup and restore it over the database; stop the server first
+    Restore { path: PathBuf },
+    /// Write all users and messages as JSON Lines ("-" for stdout)
+    Export { path: String },
+    /// Load users and messages from JSON Lines
+    Import {
+        path: PathBuf,
+        /// Check the file without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Convert timestamps written by older versions from local time to UTC
+    MigrateTimestamps {
+        /// Timezone the old timestamps were recorded in (defaults to timestamps.source_timezone)
+        timezone: Option<String>,
+    },
+    /// Check database health
+    Check {
+        /// Repair orphaned messages and duplicate users
+        #[arg(long)]
+        fix: bool,
+        /// Run VACUUM and wal_checkpoint(TRUNCATE) afterwards
+        #[arg(long)]
+        vacuum: bool,
+    },
+    /// Apply pending schema migrations
+    Migrate,
+    /// Insert the Alice and Bob example users and messages (skips what already exists)
+    Seed,
+    /// Delete every message
+    ClearMessages,
+    /// Manage users
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Manage messages
+    Message {
+        #[command(subcommand)]
+        command: MessageCommand,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration as TOML
+    Print,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// List users with their message counts
+    List,
+    /// Create a user
+    Create { name: String },
+    /// Delete a user
+    Delete {
+        name: String,
+        /// Also delete the user's messages; otherwise users with messages are kept
+        #[arg(long)]
+        with_messages: bool,
+    },
+    /// Rename a user
+    Rename { name: String, new_name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MessageCommand {
+    /// List messages in id order
+    List {
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        /// Only show messages by this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Delete a message by id
+    Delete { id: i32 },
+}
+
+/// 打印合并后的配置；配置有问题时在标准错误中列出并返回失败
+pub fn print_config(file: Option<&Path>, overrides: &ConfigOverrides) -> std::io::Result<()> {
+    let (config, mut errors) = Config::resolve(file, overrides)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    print!("{}", toml::to_string_pretty(&config).map_err(std::io::Error::other)?);
+    errors.extend(config.validate());
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+pub fn backup_database(config: &Config, path: &Path) -> std::io::Result<()> {
+    backup::backup_database(&config.database_url, path).map_err(std::io::Error::other)
+}
+
+pub fn restore_database(config: &Config, path: &Path) -> std::io::Result<()> {
+    backup::restore_database(&config.database_url, path).map_err(std::io::Error::other)
+}
+
+/// 把整个留言板以JSONL格式写到`path`，`-`表示标准输出
+pub fn export_board(pool: &Pool, path: &str) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let mut output: Box<dyn Write> = if path == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(BufWriter::new(File::create(path)?))
+    };
+    let mut cursor = transfer::ExportCursor::start();
+    loop {
+        let (chunk, next) = transfer::export_chunk(&db_connection, cursor).map_err(std::io::Error::other)?;
+        if let transfer::ExportCursor::Done = next {
+            break;
+        }
+        output.write_all(chunk.as_bytes())?;
+        cursor = next;
+    }
+    output.flush()
+}
+
+pub fn import_board(pool: &Pool, path: &Path, dry_run: bool, limits: &ContentLimits) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let input = BufReader::new(File::open(path)?);
+    let report = transfer::import_board(&db_connection, input, dry_run, limits).map_err(std::io::Error::other)?;
+    for error in &report.errors {
+        eprintln!("line {}: {}", error.line, error.error);
+    }
+    println!(
+        "{}users created: {}, users matched: {}, messages created: {}, failed lines: {}",
+        if dry_run { "[dry run] " } else { "" },
+        report.users_created,
+        report.users_matched,
+        report.messages_created,
+        report.errors.len()
+    );
+    Ok(())
+}
+
+/// 把旧版本按`source`时区记录的时间转换为UTC
+pub fn migrate_timestamps(pool: &Pool, source: &str) -> std::io::Result<()> {
+    let zone = timezone::Zone::parse(source)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let converted = timezone::convert_to_utc(&db_connection, &zone, source).map_err(std::io::Error::other)?;
+    println!("Converted {} timestamps from {} to UTC", converted, source);
+    Ok(())
+}
+
+/// 检查数据库的健康状况，`fix`时修复发现的问题，`vacuum`时再整理数据库文件
+pub fn check_database(pool: &Pool, fix: bool, vacuum: bool) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let report = maintenance::run(&db_connection, fix, vacuum).map_err(std::io::Error::other)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_healthy() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// 执行还没有执行过的迁移。迁移期间关闭外键检查，SQLite重建表时要求这样做
+pub fn run_migrations(pool: &Pool, foreign_keys: bool) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    db_connection.batch_execute("PRAGMA foreign_keys = OFF;").map_err(std::io::Error::other)?;
+    crate::embedded_migrations::run_with_output(&*db_connection, &mut std::io::stdout())
+        .map_err(std::io::Error::other)?;
+    if foreign_keys {
+        db_connection.batch_execute("PRAGMA foreign_keys = ON;").map_err(std::io::Error::other)?;
+    }
+    Ok(())
+}
+
+pub fn seed_board(pool: &Pool) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let report = seed::seed_board(&db_connection).map_err(std::io::Error::other)?;
+    println!("users created: {}, messages created: {}", report.users_created, report.messages_created);
+    Ok(())
+}
+
+/// 和`DELETE /api/v2/messages`一样产生`board.cleared`事件，由服务器的webhook任务投递
+pub fn clear_messages(pool: &Pool) -> std::io::Result<()> {
+    let deleted = operations::delete_messages(pool).map_err(api_error)?;
+    println!("Deleted {} messages", deleted);
+    Ok(())
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+/// 命令行直接输出数据库错误的原因，不像接口那样只写日志
+fn api_error(error: ApiError) -> std::io::Error {
+    match error {
+        ApiError::Database(reason) | ApiError::Unavailable(reason) => std::io::Error::other(reason),
+        error => std::io::Error::other(error.to_string()),
+    }
+}
+
+/// 用户管理，输出以制表符分隔，方便交给其他命令处理
+pub fn manage_users(pool: &Pool, command: UserCommand, limits: &ContentLimits) -> std::io::Result<()> {
+    let db_connection = pool.get().map_err(std::io::Error::other)?;
+    let existing = |user_name: &str| board::find_user(&db_connection, user_name).map_err(std::io::Error::other);
+    let check_new_name = |user_name: &str| {
+        if user_name.is_empty() || user_name.len() > limits.max_name_length {
+            return Err(invalid_input(format!("user name must be 1 to {} bytes long", limits.max_name_length)));
+        }
+        if existing(user_name)?.is_some() {
+            return Err(invalid_input(format!("user '{}' already exists", user_name)));
+        }
+        Ok(())
+    }; //新用户名要满足长度限制，而且不能和已有用户重名
+    match command {
+        UserCommand::List => {
+            for (item, count) in board::list_users(&db_connection).map_err(std::io::Error::other)? {
+                println!("{}\t{}\t{}\t{}", item.id, item.name, timezone::Zone::Utc.format(&item.register_date), count);
+            }
+        },
+        UserCommand::Create { name } => {
+            check_new_name(&name)?;
+            drop(db_connection); //`operations`自己从连接池取连接，`pool.max_size`为1时也不会等不到
+            let created = operations::create_user(pool, &name).map_err(api_error)?;
+            println!("Created user {} with id {}", created.name, created.id);
+        },
+        UserCommand::Delete { name, with_messages } => {
+            let target = existing(&name)?.ok_or_else(|| invalid_input(format!("no user named '{}'", name)))?;
+            drop(db_connection);
+            match operations::delete_user(pool, &target, with_messages).map_err(api_error)? {
+                Some(removed) => println!("Deleted user {} and {} messages", target.name, removed),
+                None => return Err(invalid_input(format!("user '{}' still has messages; pass --with-messages to delete them too", name))),
+            }
+        },
+        UserCommand::Rename { name, new_name } => {
+            let target = existing(&name)?.ok_or_else(|| invalid_input(format!("no user named '{}'", name)))?;
+            check_new_name(&new_name)?;
+            board::rename_user(&db_connection, &target, &new_name).map_err(std::io::Error::other)?;
+            println!("Renamed user {} to {}", name, new_name);
+        },
+    }
+    Ok(())
+}
+
+pub fn manage_messages(pool: &Pool, command: MessageCommand) -> std::io::Result<()> {
+    match command {
+        MessageCommand::List { limit, offset, user } => {
+            let db_connection = pool.get().map_err(std::io::Error::other)?;
+            let author = match user {
+                Some(user_name) => Some(
+                    board::find_user(&db_connection, &user_name)
+                        .map_err(std::io::Error::other)?
+                        .ok_or_else(|| invalid_input(format!("no user named '{}'", user_name)))?
+                        .id,
+                ),
+                None => None,
+            };
+            for (item, author) in board::list_messages(&db_connection, author, limit, offset).map_err(std::io::Error::other)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    item.id,
+                    author.unwrap_or_else(|| format!("#{}", item.user)),
+                    timezone::Zone::Utc.format(&item.pub_date),
+                    item.title,
+                    item.content
+                );
+            }
+        },
+        MessageCommand::Delete { id } => {
+            if !operations::delete_message(pool, id).map_err(api_error)? {
+                return Err(invalid_input(format!("no message with id {}", id)));
+            }
+            println!("Deleted message {}", id);
+        },
+    }
+    Ok(())
+}
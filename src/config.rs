@@ -1,7 +1,72 @@
-use diesel::{connection::SimpleConnection, prelude::*};
+use std::{fmt, path::{Path, PathBuf}, str::FromStr, time::Duration};
+use diesel::{connection::SimpleConnection, prelude::*, r2d2::ConnectionManager};
+use serde::{Deserialize, Serialize};
+use crate::Pool;
+use crate::models::{MAX_CONTENT_LENGTH, MAX_NAME_LENGTH, MAX_TITLE_LENGTH};
+use crate::timezone::Zone;
+
+/// 不指定配置文件时，如果当前目录下有这个文件就读取它
+pub const DEFAULT_CONFIG_FILE: &str = "backend.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl fmt::Display for Synchronous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().as_str() {
+            "off" => Ok(Synchronous::Off),
+            "normal" => Ok(Synchronous::Normal),
+            "full" => Ok(Synchronous::Full),
+            "extra" => Ok(Synchronous::Extra),
+            _ => Err(format!("{} is not one of off, normal, full, extra", text)),
+        }
+    }
+}
+
+/// 日志的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{} is not one of pretty, json", text)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ConnectionOptions {
     pub enable_wal: bool,
+    pub synchronous: Option<Synchronous>,
     pub enable_foreign_keys: bool,
     pub busy_timeout: Option<std::time::Duration>,
 }
@@ -10,17 +75,804 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
     for ConnectionOptions {
     fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
         (|| {
+            if let Some(d) = self.busy_timeout {
+                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
+            } //最先设置，这样连接池同时打开多个连接时，切换WAL也会等待锁而不是直接失败
             if self.enable_wal {
-                conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+                conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+            }
+            if let Some(s) = self.synchronous {
+                conn.batch_execute(&format!("PRAGMA synchronous = {};", s))?;
             }
             if self.enable_foreign_keys {
                 conn.batch_execute("PRAGMA foreign_keys = ON;")?;
             }
-            if let Some(d) = self.busy_timeout {
-                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
-            }
             Ok(())
         })()
         .map_err(diesel::r2d2::Error::QueryError)
     }
-}
\ No newline at end of file
+}
+
+/// 全部配置。依次读取配置文件、环境变量和命令行参数，后者覆盖前者
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database_url: String,
+    pub server: ServerConfig,
+    pub pool: PoolConfig,
+    pub sqlite: SqliteConfig,
+    pub limits: ContentLimits,
+    pub backup: BackupConfig,
+    pub retention: RetentionConfig,
+    pub maintenance: MaintenanceConfig,
+    pub webhooks: WebhookConfig,
+    pub timestamps: TimestampConfig,
+    pub log: LogConfig,
+    pub tls: TlsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub api: ApiConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<String>,
+    pub workers: Option<usize>, //不设置时actix按CPU核数启动
+    pub shutdown_timeout_secs: u64, //收到SIGTERM后等待处理中的请求完成的最长时间
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub wal: bool,
+    pub synchronous: Option<Synchronous>,
+    pub foreign_keys: bool,
+    pub busy_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentLimits {
+    pub max_name_length: usize,
+    pub max_title_length: usize,
+    pub max_content_length: usize,
+    pub import_payload_bytes: usize,
+    pub message_payload_bytes: usize, //POST /api/message的请求体上限，超过时返回413
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub directory: PathBuf,
+    pub interval_secs: Option<u64>, //不设置或为0时不做定时备份
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub max_age_days: Option<i64>,
+    pub keep_newest: Option<i64>,
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub interval_secs: Option<u64>,
+}
+
+/// 投递失败后等待`retry_base_secs`、`2 * retry_base_secs`……重试，最长间隔`retry_max_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub poll_interval_secs: u64, //多久检查一次待投递的事件
+    pub timeout_secs: u64,
+    pub max_attempts: i32, //尝试这么多次仍然失败就不再重试
+    pub retry_base_secs: u64,
+    pub retry_max_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimestampConfig {
+    pub source_timezone: String, //转换旧数据时默认使用的时区
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub filter: String, //tracing-subscriber的EnvFilter语法，例如"info,backend_demo=debug"
+}
+
+/// 同时设置了`cert`和`key`时启用HTTPS
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub redirect_listen: Vec<String>, //在这些地址上监听HTTP，并重定向到HTTPS
+}
+
+/// 令牌桶的配额：最多连续`burst`个请求，之后每分钟恢复`per_minute`个。`burst`为0时不限流
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Quota {
+    pub fn is_enabled(&self) -> bool {
+        self.burst > 0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub get_message: Quota,
+    pub post_message: Quota,
+    pub trust_forwarded_for: bool, //在反向代理后面时按X-Forwarded-For中的地址限流
+}
+
+/// 跨域策略。`allowed_origins`为空时不允许跨域，`"*"`表示任意来源
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+    pub routes: Vec<CorsRouteConfig>,
+}
+
+/// 接口版本相关的设置
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub django_compat: bool, //v1的错误响应和原来的Django版本一样只返回文本，默认打开
+    pub graphiql: bool, //在GET /api/graphql提供GraphiQL页面，默认只在debug构建中打开
+    pub event_buffer: usize, //每个实时连接最多积压的事件数，超过时断开这个连接
+    pub heartbeat_secs: u64, //实时连接发送心跳的间隔
+    pub client_timeout_secs: u64, //这么久没有收到客户端的任何数据就断开WebSocket
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            django_compat: true,
+            graphiql: cfg!(debug_assertions),
+            event_buffer: 256,
+            heartbeat_secs: 15,
+            client_timeout_secs: 45,
+        }
+    }
+}
+
+/// 对某个路径前缀覆盖默认的跨域策略，没写的项沿用默认值
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsRouteConfig {
+    pub path: String,
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database_url: String::new(),
+            server: ServerConfig::default(),
+            pool: PoolConfig::default(),
+            sqlite: SqliteConfig::default(),
+            limits: ContentLimits::default(),
+            backup: BackupConfig::default(),
+            retention: RetentionConfig {
+                interval_secs: 3600,
+                ..Default::default()
+            },
+            maintenance: MaintenanceConfig::default(),
+            webhooks: WebhookConfig::default(),
+            timestamps: TimestampConfig::default(),
+            log: LogConfig::default(),
+            tls: TlsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            cors: CorsConfig::default(),
+            api: ApiConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![String::from("127.0.0.1:8000")],
+            workers: None,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 16,
+            min_idle: None,
+            connection_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            wal: true,
+            synchronous: Some(Synchronous::Normal),
+            foreign_keys: true,
+            busy_timeout_ms: Some(30_000),
+        }
+    }
+}
+
+impl Default for ContentLimits {
+    fn default() -> Self {
+        ContentLimits {
+            max_name_length: MAX_NAME_LENGTH,
+            max_title_length: MAX_TITLE_LENGTH,
+            max_content_length: MAX_CONTENT_LENGTH,
+            import_payload_bytes: 64 * 1024 * 1024,
+            message_payload_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            directory: PathBuf::from("backups"),
+            interval_secs: None,
+            keep: 7,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            poll_interval_secs: 1,
+            timeout_secs: 10,
+            max_attempts: 8,
+            retry_base_secs: 10,
+            retry_max_secs: 3600,
+        }
+    }
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        TimestampConfig {
+            source_timezone: String::from("local"),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Pretty,
+            filter: String::from("info"),
+        }
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            burst: 10,
+            per_minute: 60,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            get_message: Quota { burst: 120, per_minute: 600 },
+            post_message: Quota { burst: 10, per_minute: 30 },
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![String::from("GET"), String::from("POST")],
+            allowed_headers: vec![String::from("content-type")],
+            allow_credentials: false,
+            max_age_secs: Some(3600),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// 命令行上可以覆盖的配置项
+#[derive(Debug, Default, clap::Args)]
+pub struct ConfigOverrides {
+    /// SQLite database file
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+    /// Address to listen on, can be repeated
+    #[arg(long, global = true)]
+    pub listen: Vec<String>,
+    /// Number of HTTP worker threads
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    /// Maximum number of pooled connections
+    #[arg(long, global = true)]
+    pub pool_max_size: Option<u32>,
+    /// Minimum number of idle pooled connections
+    #[arg(long, global = true)]
+    pub pool_min_idle: Option<u32>,
+    /// Seconds to wait for a pooled connection
+    #[arg(long, global = true)]
+    pub pool_timeout_secs: Option<u64>,
+    /// Use the WAL journal mode
+    #[arg(long, global = true)]
+    pub sqlite_wal: Option<bool>,
+    /// PRAGMA synchronous: off, normal, full or extra
+    #[arg(long, global = true)]
+    pub sqlite_synchronous: Option<Synchronous>,
+    /// Enforce foreign keys
+    #[arg(long, global = true)]
+    pub sqlite_foreign_keys: Option<bool>,
+    /// PRAGMA busy_timeout in milliseconds
+    #[arg(long, global = true)]
+    pub sqlite_busy_timeout_ms: Option<u64>,
+    /// Longest accepted user name
+    #[arg(long, global = true)]
+    pub max_name_length: Option<usize>,
+    /// Longest accepted message title
+    #[arg(long, global = true)]
+    pub max_title_length: Option<usize>,
+    /// Longest accepted message content
+    #[arg(long, global = true)]
+    pub max_content_length: Option<usize>,
+    /// Log output: pretty or json
+    #[arg(long, global = true)]
+    pub log_format: Option<LogFormat>,
+    /// Log filter, e.g. "info" or "backend_demo=debug"
+    #[arg(long, global = true)]
+    pub log_filter: Option<String>,
+    /// PEM certificate chain; enables HTTPS together with --tls-key
+    #[arg(long, global = true)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key
+    #[arg(long, global = true)]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Config {
+    /// 按"默认值 < 配置文件 < 环境变量 < 命令行参数"的顺序加载配置，并检查配置是否合法。
+    /// 配置文件由`--config`或`CONFIG_FILE`指定，都没有时尝试读取`backend.toml`
+    pub fn load(file: Option<&Path>, overrides: &ConfigOverrides) -> Result<Self, Vec<String>> {
+        Config::load_with(file, overrides, &process_env)
+    }
+
+    /// 和`load`相同，但从`env`而不是进程的环境变量中读取
+    pub fn load_with(file: Option<&Path>, overrides: &ConfigOverrides, env: &dyn Fn(&str) -> Option<String>) -> Result<Self, Vec<String>> {
+        let (config, mut errors) = Config::resolve_with(file, overrides, env).map_err(|e| vec![e])?;
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// 合并各层配置但不做检查，同时返回无法解析的环境变量。配置文件无法读取时直接失败
+    pub fn resolve(file: Option<&Path>, overrides: &ConfigOverrides) -> Result<(Self, Vec<String>), String> {
+        Config::resolve_with(file, overrides, &process_env)
+    }
+
+    fn resolve_with(file: Option<&Path>, overrides: &ConfigOverrides, env: &dyn Fn(&str) -> Option<String>) -> Result<(Self, Vec<String>), String> {
+        let file = file
+            .map(Path::to_path_buf)
+            .or_else(|| env("CONFIG_FILE").map(PathBuf::from));
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        let errors = config.apply_env(env);
+        config.apply_overrides(overrides);
+        Ok((config, errors))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// 用环境变量覆盖配置，返回无法解析的环境变量。`env`按名字查找变量
+    pub fn apply_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut errors = Vec::new();
+        env_value(env, "DATABASE_URL", &mut self.database_url, &mut errors);
+        if let Some(listen) = env("LISTEN") {
+            self.server.listen = listen.split(',').map(|address| address.trim().to_string()).collect();
+        }
+        env_option(env, "WORKERS", &mut self.server.workers, &mut errors);
+        env_value(env, "SHUTDOWN_TIMEOUT_SECS", &mut self.server.shutdown_timeout_secs, &mut errors);
+        env_value(env, "POOL_MAX_SIZE", &mut self.pool.max_size, &mut errors);
+        env_option(env, "POOL_MIN_IDLE", &mut self.pool.min_idle, &mut errors);
+        env_value(env, "POOL_TIMEOUT_SECS", &mut self.pool.connection_timeout_secs, &mut errors);
+        env_value(env, "SQLITE_WAL", &mut self.sqlite.wal, &mut errors);
+        env_option(env, "SQLITE_SYNCHRONOUS", &mut self.sqlite.synchronous, &mut errors);
+        env_value(env, "SQLITE_FOREIGN_KEYS", &mut self.sqlite.foreign_keys, &mut errors);
+        env_option(env, "SQLITE_BUSY_TIMEOUT_MS", &mut self.sqlite.busy_timeout_ms, &mut errors);
+        env_value(env, "MAX_NAME_LENGTH", &mut self.limits.max_name_length, &mut errors);
+        env_value(env, "MAX_TITLE_LENGTH", &mut self.limits.max_title_length, &mut errors);
+        env_value(env, "MAX_CONTENT_LENGTH", &mut self.limits.max_content_length, &mut errors);
+        env_value(env, "IMPORT_PAYLOAD_BYTES", &mut self.limits.import_payload_bytes, &mut errors);
+        env_value(env, "MESSAGE_PAYLOAD_BYTES", &mut self.limits.message_payload_bytes, &mut errors);
+        env_value(env, "BACKUP_DIR", &mut self.backup.directory, &mut errors);
+        env_option(env, "BACKUP_INTERVAL_SECS", &mut self.backup.interval_secs, &mut errors);
+        env_value(env, "BACKUP_KEEP", &mut self.backup.keep, &mut errors);
+        env_option(env, "RETENTION_MAX_AGE_DAYS", &mut self.retention.max_age_days, &mut errors);
+        env_option(env, "RETENTION_KEEP_NEWEST", &mut self.retention.keep_newest, &mut errors);
+        env_value(env, "RETENTION_INTERVAL_SECS", &mut self.retention.interval_secs, &mut errors);
+        env_option(env, "MAINTENANCE_INTERVAL_SECS", &mut self.maintenance.interval_secs, &mut errors);
+        env_value(env, "WEBHOOK_POLL_INTERVAL_SECS", &mut self.webhooks.poll_interval_secs, &mut errors);
+        env_value(env, "WEBHOOK_TIMEOUT_SECS", &mut self.webhooks.timeout_secs, &mut errors);
+        env_value(env, "WEBHOOK_MAX_ATTEMPTS", &mut self.webhooks.max_attempts, &mut errors);
+        env_value(env, "WEBHOOK_RETRY_BASE_SECS", &mut self.webhooks.retry_base_secs, &mut errors);
+        env_value(env, "WEBHOOK_RETRY_MAX_SECS", &mut self.webhooks.retry_max_secs, &mut errors);
+        env_value(env, "TIMESTAMP_SOURCE_TZ", &mut self.timestamps.source_timezone, &mut errors);
+        env_value(env, "LOG_FORMAT", &mut self.log.format, &mut errors);
+        env_value(env, "LOG_FILTER", &mut self.log.filter, &mut errors);
+        env_option(env, "TLS_CERT", &mut self.tls.cert, &mut errors);
+        env_option(env, "TLS_KEY", &mut self.tls.key, &mut errors);
+        if let Some(listen) = env("TLS_REDIRECT_LISTEN") {
+            self.tls.redirect_listen = listen
+                .split(',')
+                .map(|address| address.trim().to_string())
+                .filter(|address| !address.is_empty())
+                .collect();
+        }
+        env_value(env, "RATE_LIMIT_GET_BURST", &mut self.rate_limit.get_message.burst, &mut errors);
+        env_value(env, "RATE_LIMIT_GET_PER_MINUTE", &mut self.rate_limit.get_message.per_minute, &mut errors);
+        env_value(env, "RATE_LIMIT_POST_BURST", &mut self.rate_limit.post_message.burst, &mut errors);
+        env_value(env, "RATE_LIMIT_POST_PER_MINUTE", &mut self.rate_limit.post_message.per_minute, &mut errors);
+        env_value(env, "RATE_LIMIT_TRUST_FORWARDED_FOR", &mut self.rate_limit.trust_forwarded_for, &mut errors);
+        if let Some(origins) = env("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        env_value(env, "CORS_ALLOW_CREDENTIALS", &mut self.cors.allow_credentials, &mut errors);
+        env_value(env, "API_DJANGO_COMPAT", &mut self.api.django_compat, &mut errors);
+        env_value(env, "API_GRAPHIQL", &mut self.api.graphiql, &mut errors);
+        env_value(env, "API_EVENT_BUFFER", &mut self.api.event_buffer, &mut errors);
+        env_value(env, "API_HEARTBEAT_SECS", &mut self.api.heartbeat_secs, &mut errors);
+        env_value(env, "API_CLIENT_TIMEOUT_SECS", &mut self.api.client_timeout_secs, &mut errors);
+        errors
+    }
+
+    pub fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(database_url) = &overrides.database_url {
+            self.database_url = database_url.clone();
+        }
+        if !overrides.listen.is_empty() {
+            self.server.listen = overrides.listen.clone();
+        }
+        self.server.workers = overrides.workers.or(self.server.workers);
+        self.pool.max_size = overrides.pool_max_size.unwrap_or(self.pool.max_size);
+        self.pool.min_idle = overrides.pool_min_idle.or(self.pool.min_idle);
+        self.pool.connection_timeout_secs = overrides.pool_timeout_secs.unwrap_or(self.pool.connection_timeout_secs);
+        self.sqlite.wal = overrides.sqlite_wal.unwrap_or(self.sqlite.wal);
+        self.sqlite.synchronous = overrides.sqlite_synchronous.or(self.sqlite.synchronous);
+        self.sqlite.foreign_keys = overrides.sqlite_foreign_keys.unwrap_or(self.sqlite.foreign_keys);
+        self.sqlite.busy_timeout_ms = overrides.sqlite_busy_timeout_ms.or(self.sqlite.busy_timeout_ms);
+        self.limits.max_name_length = overrides.max_name_length.unwrap_or(self.limits.max_name_length);
+        self.limits.max_title_length = overrides.max_title_length.unwrap_or(self.limits.max_title_length);
+        self.limits.max_content_length = overrides.max_content_length.unwrap_or(self.limits.max_content_length);
+        self.log.format = overrides.log_format.unwrap_or(self.log.format);
+        if let Some(filter) = &overrides.log_filter {
+            self.log.filter = filter.clone();
+        }
+        self.tls.cert = overrides.tls_cert.clone().or_else(|| self.tls.cert.take());
+        self.tls.key = overrides.tls_key.clone().or_else(|| self.tls.key.take());
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.database_url.is_empty() {
+            errors.push(String::from("Unable to locate the database. Try setting the 'DATABASE_URL' variable."));
+        }
+        if self.server.listen.is_empty() {
+            errors.push(String::from("server.listen must contain at least one address"));
+        }
+        for address in &self.server.listen {
+            if listen_port(address).is_none() {
+                errors.push(format!("server.listen: '{}' is not a host:port address", address));
+            }
+        }
+        if self.server.workers == Some(0) {
+            errors.push(String::from("server.workers must be at least 1"));
+        }
+        if self.pool.max_size == 0 {
+            errors.push(String::from("pool.max_size must be at least 1"));
+        }
+        if let Some(min_idle) = self.pool.min_idle {
+            if min_idle > self.pool.max_size {
+                errors.push(format!("pool.min_idle ({}) exceeds pool.max_size ({})", min_idle, self.pool.max_size));
+            }
+        }
+        if self.pool.connection_timeout_secs == 0 {
+            errors.push(String::from("pool.connection_timeout_secs must be at least 1"));
+        }
+        for (name, value) in &[
+            ("limits.max_name_length", self.limits.max_name_length),
+            ("limits.max_title_length", self.limits.max_title_length),
+            ("limits.max_content_length", self.limits.max_content_length),
+            ("limits.import_payload_bytes", self.limits.import_payload_bytes),
+            ("limits.message_payload_bytes", self.limits.message_payload_bytes),
+        ] {
+            if *value == 0 {
+                errors.push(format!("{} must be at least 1", name));
+            }
+        }
+        if self.api.event_buffer == 0 {
+            errors.push(String::from("api.event_buffer must be at least 1"));
+        }
+        if self.api.heartbeat_secs == 0 {
+            errors.push(String::from("api.heartbeat_secs must be at least 1"));
+        }
+        if self.api.client_timeout_secs <= self.api.heartbeat_secs {
+            errors.push(format!(
+                "api.client_timeout_secs ({}) must be greater than api.heartbeat_secs ({})",
+                self.api.client_timeout_secs, self.api.heartbeat_secs
+            ));
+        }
+        if self.backup.keep == 0 {
+            errors.push(String::from("backup.keep must be at least 1"));
+        }
+        if matches!(self.retention.max_age_days, Some(days) if days <= 0) {
+            errors.push(String::from("retention.max_age_days must be at least 1"));
+        }
+        if matches!(self.retention.keep_newest, Some(keep) if keep < 0) {
+            errors.push(String::from("retention.keep_newest must not be negative"));
+        }
+        if self.retention.interval_secs == 0 {
+            errors.push(String::from("retention.interval_secs must be at least 1"));
+        }
+        for (name, value) in [
+            ("webhooks.poll_interval_secs", self.webhooks.poll_interval_secs),
+            ("webhooks.timeout_secs", self.webhooks.timeout_secs),
+            ("webhooks.retry_base_secs", self.webhooks.retry_base_secs),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be at least 1", name));
+            }
+        }
+        if self.webhooks.max_attempts < 1 {
+            errors.push(String::from("webhooks.max_attempts must be at least 1"));
+        }
+        if self.webhooks.retry_max_secs < self.webhooks.retry_base_secs {
+            errors.push(format!(
+                "webhooks.retry_max_secs ({}) must not be less than webhooks.retry_base_secs ({})",
+                self.webhooks.retry_max_secs, self.webhooks.retry_base_secs
+            ));
+        }
+        if let Err(e) = Zone::parse(&self.timestamps.source_timezone) {
+            errors.push(format!("timestamps.source_timezone: {}", e));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.filter) {
+            errors.push(format!("log.filter: {}", e));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            errors.push(String::from("tls.cert and tls.key must be set together"));
+        }
+        if !self.tls.redirect_listen.is_empty() && self.tls.cert.is_none() {
+            errors.push(String::from("tls.redirect_listen requires tls.cert and tls.key"));
+        }
+        for (name, quota) in &[
+            ("rate_limit.get_message", self.rate_limit.get_message),
+            ("rate_limit.post_message", self.rate_limit.post_message),
+        ] {
+            if quota.is_enabled() && quota.per_minute == 0 {
+                errors.push(format!("{}.per_minute must be at least 1 when burst is set", name));
+            }
+        }
+        let cors_policies = std::iter::once((
+            String::from("cors"),
+            &self.cors.allowed_origins,
+            &self.cors.allowed_methods,
+            self.cors.allow_credentials,
+        ))
+        .chain(self.cors.routes.iter().map(|route| (
+            format!("cors.routes '{}'", route.path),
+            route.allowed_origins.as_ref().unwrap_or(&self.cors.allowed_origins),
+            route.allowed_methods.as_ref().unwrap_or(&self.cors.allowed_methods),
+            route.allow_credentials.unwrap_or(self.cors.allow_credentials),
+        )));
+        for (name, origins, methods, credentials) in cors_policies {
+            if credentials && origins.iter().any(|origin| origin == "*") {
+                errors.push(format!("{}: allow_credentials cannot be combined with the \"*\" origin", name));
+            }
+            for method in methods {
+                if actix_web::http::Method::from_str(&method.to_ascii_uppercase()).is_err() {
+                    errors.push(format!("{}: '{}' is not an HTTP method", name, method));
+                }
+            }
+        }
+        for route in &self.cors.routes {
+            if !route.path.starts_with('/') {
+                errors.push(format!("cors.routes: path '{}' must start with '/'", route.path));
+            }
+        }
+        for address in &self.tls.redirect_listen {
+            if listen_port(address).is_none() {
+                errors.push(format!("tls.redirect_listen: '{}' is not a host:port address", address));
+            }
+        }
+        errors
+    }
+
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            enable_wal: self.sqlite.wal,
+            synchronous: self.sqlite.synchronous,
+            enable_foreign_keys: self.sqlite.foreign_keys,
+            busy_timeout: self.sqlite.busy_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    pub fn build_pool(&self) -> Result<Pool, diesel::r2d2::PoolError> {
+        Pool::builder()
+            .max_size(self.pool.max_size)
+            .min_idle(self.pool.min_idle)
+            .connection_timeout(Duration::from_secs(self.pool.connection_timeout_secs))
+            .connection_customizer(Box::new(self.connection_options()))
+            .event_handler(Box::new(crate::metrics::PoolMetrics))
+            .build(ConnectionManager::<SqliteConnection>::new(self.database_url.as_str()))
+    }
+
+    pub fn backup_options(&self) -> BackupOptions {
+        BackupOptions {
+            database_url: self.database_url.clone(),
+            directory: self.backup.directory.clone(),
+            interval: self.backup.interval_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            keep: self.backup.keep,
+        }
+    }
+
+    pub fn retention_options(&self) -> RetentionOptions {
+        RetentionOptions {
+            max_age: self.retention.max_age_days.map(chrono::Duration::days),
+            keep_newest: self.retention.keep_newest,
+            interval: Duration::from_secs(self.retention.interval_secs),
+        }
+    }
+
+    /// 没有启用HTTPS时返回`None`。重定向的目标端口取第一个监听地址的端口
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsOptions {
+                cert: cert.clone(),
+                key: key.clone(),
+                redirect_listen: self.tls.redirect_listen.clone(),
+                https_port: self.server.listen.first().and_then(|address| listen_port(address)).unwrap_or(443),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn maintenance_options(&self) -> MaintenanceOptions {
+        MaintenanceOptions {
+            interval: self.maintenance.interval_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+        }
+    }
+
+    pub fn webhook_options(&self) -> WebhookOptions {
+        WebhookOptions {
+            poll_interval: Duration::from_secs(self.webhooks.poll_interval_secs),
+            timeout: Duration::from_secs(self.webhooks.timeout_secs),
+            max_attempts: self.webhooks.max_attempts,
+            retry_base: Duration::from_secs(self.webhooks.retry_base_secs),
+            retry_max: Duration::from_secs(self.webhooks.retry_max_secs),
+        }
+    }
+}
+
+/// `host:port`形式的监听地址中的端口，地址不合法时返回`None`
+fn listen_port(address: &str) -> Option<u16> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => port.parse().ok(),
+        _ => None,
+    }
+}
+
+fn process_env(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+fn env_value<T: FromStr>(env: &dyn Fn(&str) -> Option<String>, key: &str, target: &mut T, errors: &mut Vec<String>) {
+    if let Some(text) = env(key) {
+        match text.parse() {
+            Ok(value) => *target = value,
+            Err(_) => errors.push(format!("{}: cannot parse '{}'", key, text)),
+        }
+    }
+}
+
+fn env_option<T: FromStr>(env: &dyn Fn(&str) -> Option<String>, key: &str, target: &mut Option<T>, errors: &mut Vec<String>) {
+    if let Some(text) = env(key) {
+        match text.parse() {
+            Ok(value) => *target = Some(value),
+            Err(_) => errors.push(format!("{}: cannot parse '{}'", key, text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    pub database_url: String,
+    pub directory: std::path::PathBuf,
+    pub interval: Option<std::time::Duration>,
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionOptions {
+    pub max_age: Option<chrono::Duration>,
+    pub keep_newest: Option<i64>,
+    pub interval: std::time::Duration,
+}
+
+impl RetentionOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.keep_newest.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceOptions {
+    pub interval: Option<std::time::Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    pub poll_interval: std::time::Duration,
+    pub timeout: std::time::Duration,
+    pub max_attempts: i32,
+    pub retry_base: std::time::Duration,
+    pub retry_max: std::time::Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub redirect_listen: Vec<String>,
+    pub https_port: u16,
+}
@@ -0,0 +1,236 @@
+use std::{pin::Pin, str::FromStr, sync::Arc, task::{Context, Poll}};
+use actix_web::{
+    Error, HttpRequest, HttpResponse, ResponseError, dev::{ConnectionInfo, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{HeaderMap, HeaderValue, Method, header},
+};
+use futures::future::{Either, Future, Ready, ok};
+use crate::config::{CorsConfig, CorsRouteConfig};
+use crate::error::ApiError;
+
+/// 管理接口不允许跨域访问，配置中的覆盖规则也不能打开
+pub const ADMIN_PREFIXES: [&str; 3] = ["/api/admin", "/api/clearmessage", "/api/v1/clearmessage"];
+
+/// 和普通接口共用路径的管理操作，按方法区分
+pub const ADMIN_OPERATIONS: [(Method, &str); 1] = [(Method::DELETE, "/api/v2/messages")];
+
+fn is_admin_operation(method: &str, path: &str) -> bool {
+    ADMIN_OPERATIONS.iter().any(|(admin_method, admin_path)| admin_method.as_str() == method && *admin_path == path)
+}
+
+/// `Origin`和请求本身的`scheme://host`相同。同源的页面（比如GraphiQL）发POST时浏览器也会带上`Origin`
+fn is_same_origin(origin: &HeaderValue, connection_info: &ConnectionInfo) -> bool {
+    let own = format!("{}://{}", connection_info.scheme(), connection_info.host());
+    origin.to_str().is_ok_and(|origin| origin.eq_ignore_ascii_case(&own))
+}
+
+/// 来自其他源的请求，判断规则和`CorsMiddleware`一致
+pub fn is_cross_origin(request: &HttpRequest) -> bool {
+    request.headers().get(header::ORIGIN).is_some_and(|origin| !is_same_origin(origin, &request.connection_info()))
+}
+
+fn forbidden() -> HttpResponse {
+    ApiError::Forbidden(String::from("Cross-origin request not allowed")).error_response()
+}
+
+fn is_admin(method: &str, path: &str) -> bool {
+    ADMIN_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) || is_admin_operation(method, path)
+}
+
+/// 解析好的跨域策略
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    allow_credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+impl CorsPolicy {
+    fn new(
+        origins: &[String],
+        methods: &[String],
+        headers: &[String],
+        allow_credentials: bool,
+        max_age_secs: Option<u64>,
+    ) -> Self {
+        CorsPolicy {
+            any_origin: origins.iter().any(|origin| origin == "*"),
+            origins: origins.iter().map(|origin| origin.trim_end_matches('/').to_ascii_lowercase()).collect(),
+            methods: methods.iter().filter_map(|method| Method::from_str(&method.to_ascii_uppercase()).ok()).collect(),
+            headers: headers.iter().map(|name| name.to_ascii_lowercase()).collect(),
+            allow_credentials,
+            max_age_secs,
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// 预检请求要求的方法和请求头都在允许的范围内
+    fn allows_preflight(&self, method: &str, headers: &str) -> bool {
+        let method_allowed = Method::from_str(method).is_ok_and(|method| self.methods.contains(&method));
+        let headers_allowed = headers
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .all(|name| self.headers.contains(&name));
+        method_allowed && headers_allowed
+    }
+
+    fn set_origin_headers(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone()); //带cookie时不能用"*"，总是回显请求的Origin
+        if self.allow_credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+/// 默认策略加上按路径前缀的覆盖规则，最长的前缀优先
+#[derive(Debug)]
+pub struct CorsPolicies {
+    default: Option<CorsPolicy>,
+    routes: Vec<(String, Option<CorsPolicy>)>,
+}
+
+impl CorsPolicies {
+    pub fn new(config: &CorsConfig) -> Self {
+        let policy = |route: Option<&CorsRouteConfig>| {
+            let origins = route.and_then(|r| r.allowed_origins.as_ref()).unwrap_or(&config.allowed_origins);
+            if origins.is_empty() {
+                return None;
+            }
+            Some(CorsPolicy::new(
+                origins,
+                route.and_then(|r| r.allowed_methods.as_ref()).unwrap_or(&config.allowed_methods),
+                route.and_then(|r| r.allowed_headers.as_ref()).unwrap_or(&config.allowed_headers),
+                route.and_then(|r| r.allow_credentials).unwrap_or(config.allow_credentials),
+                route.and_then(|r| r.max_age_secs).or(config.max_age_secs),
+            ))
+        }; //覆盖规则中没写的项沿用默认值，`allowed_origins = []`表示这个路径不允许跨域
+        let mut routes: Vec<(String, Option<CorsPolicy>)> = config
+            .routes
+            .iter()
+            .map(|route| (route.path.clone(), policy(Some(route))))
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        CorsPolicies { default: policy(None), routes }
+    }
+
+    pub fn policy_for(&self, path: &str) -> Option<&CorsPolicy> {
+        if ADMIN_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+            return None;
+        }
+        match self.routes.iter().find(|(prefix, _)| path.starts_with(prefix.as_str())) {
+            Some((_, policy)) => policy.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+}
+
+pub struct Cors(pub Arc<CorsPolicies>);
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware { service, policies: self.0.clone() })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    policies: Arc<CorsPolicies>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let origin = match request.headers().get(header::ORIGIN) {
+            Some(origin) if !is_same_origin(origin, &request.connection_info()) => origin.clone(),
+            _ => return Either::Right(Box::pin(self.service.call(request))), //不是跨域请求
+        };
+        let preflight_method = request
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let method = match (request.method(), &preflight_method) {
+            (&Method::OPTIONS, Some(method)) => method.as_str(),
+            (method, _) => method.as_str(),
+        };
+        let policy = self
+            .policies
+            .policy_for(request.path())
+            .filter(|_| !is_admin_operation(method, request.path()))
+            .filter(|policy| origin.to_str().is_ok_and(|origin| policy.allows_origin(origin)))
+            .cloned();
+        if let (&Method::OPTIONS, Some(method)) = (request.method(), preflight_method) {
+            let requested_headers = request
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            let response = match policy.filter(|policy| policy.allows_preflight(&method, requested_headers)) {
+                Some(policy) => {
+                    let mut response = HttpResponse::NoContent().finish();
+                    let headers = response.headers_mut();
+                    policy.set_origin_headers(headers, &origin);
+                    let methods: Vec<&str> = policy.methods.iter().map(Method::as_str).collect();
+                    if let Ok(value) = HeaderValue::from_str(&methods.join(", ")) {
+                        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, value);
+                    }
+                    if let Ok(value) = HeaderValue::from_str(&policy.headers.join(", ")) {
+                        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+                    }
+                    if let Some(max_age) = policy.max_age_secs {
+                        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+                    }
+                    response
+                },
+                None => forbidden(),
+            }; //预检请求直接在这里回答，不交给处理函数
+            return Either::Left(ok(request.into_response(response.into_body())));
+        }
+        if is_admin(request.method().as_str(), request.path()) {
+            return Either::Left(ok(request.into_response(forbidden().into_body())));
+        } //浏览器拦截响应之前请求已经执行了，跨域的管理操作不能交给处理函数
+        let future = self.service.call(request);
+        Either::Right(Box::pin(async move {
+            let mut response = future.await?;
+            if let Some(policy) = policy {
+                policy.set_origin_headers(response.headers_mut(), &origin);
+            } //不允许的来源不加任何头，由浏览器拦截
+            Ok(response)
+        }))
+    }
+}
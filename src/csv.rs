@@ -0,0 +1,121 @@
+use actix_web::{HttpMessage, HttpRequest, http::header::Accept};
+use qstring::QString;
+use crate::error::ApiError;
+use crate::models::MessageJson;
+
+/// Excel需要BOM才能认出UTF-8编码的CSV
+pub const UTF8_BOM: &str = "\u{feff}";
+
+pub const CONTENT_TYPE: &str = "text/csv; charset=utf-8; header=present";
+
+/// 列表接口的输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    Json,
+    Csv { bom: bool },
+}
+
+impl ListFormat {
+    /// `?format=`优先，其次按`Accept`中`text/csv`和`application/json`的权重选择，默认JSON。
+    /// `?bom=true`时CSV以BOM开头
+    pub fn negotiate(request: &HttpRequest) -> Result<Self, ApiError> {
+        let query_string = QString::from(request.query_string());
+        let bom = match query_string.get("bom") {
+            Some("true") | Some("1") => true,
+            Some("false") | Some("0") | None => false,
+            Some(other) => return Err(ApiError::invalid_field("bom", format!("{} is not a boolean", other))),
+        };
+        let csv = match query_string.get("format") {
+            Some(format) if format.eq_ignore_ascii_case("csv") => true,
+            Some(format) if format.eq_ignore_ascii_case("json") => false,
+            Some(format) => return Err(ApiError::invalid_field("format", format!("Unsupported format '{}', use json or csv", format))),
+            None => prefers_csv(request),
+        };
+        Ok(if csv { ListFormat::Csv { bom } } else { ListFormat::Json })
+    }
+
+    /// 用来区分ETag
+    pub fn key(self) -> &'static str {
+        match self {
+            ListFormat::Json => "json",
+            ListFormat::Csv { bom: false } => "csv",
+            ListFormat::Csv { bom: true } => "csv-bom",
+        }
+    }
+}
+
+fn prefers_csv(request: &HttpRequest) -> bool {
+    let accept = match request.get_header::<Accept>() {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let weight = |essence: &str| {
+        accept
+            .iter()
+            .filter(|item| item.item.essence_str() == essence)
+            .map(|item| item.quality)
+            .max()
+    };
+    match (weight("text/csv"), weight("application/json")) {
+        (Some(csv), Some(json)) => csv > json,
+        (Some(csv), None) => weight("*/*").is_none_or(|any| csv >= any),
+        (None, _) => false,
+    }
+}
+
+/// 可以写成CSV的一行记录
+pub trait CsvRecord {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+impl CsvRecord for MessageJson {
+    const HEADER: &'static [&'static str] = &["id", "user", "title", "content", "pub_date"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![self.id.to_string(), self.user.to_string(), text_cell(&self.title), text_cell(&self.content), self.pub_date.clone()]
+    }
+}
+
+/// 用户输入的文本以`=`、`+`、`-`、`@`、制表符或回车开头时，表格软件会当作公式执行，前面加一个`'`。
+/// 这会改变导出的内容，README和OpenAPI文档里都有说明
+fn text_cell(text: &str) -> String {
+    match text.chars().next() {
+        Some('=') | Some('+') | Some('-') | Some('@') | Some('\t') | Some('\r') => format!("'{}", text),
+        _ => String::from(text),
+    }
+}
+
+/// 按RFC 4180写一行：含逗号、引号或换行的字段用引号括起来，引号写两遍，行尾是CRLF
+pub fn write_row<S: AsRef<str>>(out: &mut String, fields: &[S]) {
+    for (index, field) in fields.iter().enumerate() {
+        let field = field.as_ref();
+        if index > 0 {
+            out.push(',');
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+/// BOM（可选）和表头
+pub fn header<T: CsvRecord>(bom: bool) -> String {
+    let mut out = String::from(if bom { UTF8_BOM } else { "" });
+    write_row(&mut out, T::HEADER);
+    out
+}
+
+pub fn rows<T: CsvRecord>(records: &[T]) -> String {
+    let mut out = String::new();
+    for record in records {
+        write_row(&mut out, &record.fields());
+    }
+    out
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use actix_web::{HttpResponse, ResponseError, error::{BlockingError, PayloadError}, http::StatusCode};
+use schemars::JsonSchema;
+use serde::Serialize;
+use crate::logging;
+
+/// 接口返回的错误。响应体统一为`{code, message, field, request_id}`的JSON
+#[derive(Debug)]
+pub enum ApiError {
+    /// 某个字段的值不合法，`field`是字段名
+    InvalidField { field: &'static str, message: String },
+    /// 请求体无法解析
+    InvalidBody(String),
+    NotFound(String),
+    /// 当前配置下不能执行的操作，比如没有设置保留策略时手动清理
+    Conflict(String),
+    /// 不允许的操作，比如跨域的管理操作
+    Forbidden(String),
+    PayloadTooLarge,
+    UnsupportedMediaType(String),
+    /// 超出限流配额，值是下一个令牌可用还需要的秒数
+    TooManyRequests(u64),
+    /// 取不到数据库连接，一般是连接池已满或数据库被锁住
+    Unavailable(String),
+    /// 数据库查询失败，详细原因只写日志，不返回给客户端
+    Database(String),
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorBody<'a> {
+    pub code: &'static str,
+    pub message: String,
+    pub field: Option<&'a str>,
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn invalid_field(field: &'static str, message: impl Into<String>) -> Self {
+        ApiError::InvalidField { field, message: message.into() }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidField { .. } => "invalid_field",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::TooManyRequests(_) => "rate_limited",
+            ApiError::Unavailable(_) => "service_unavailable",
+            ApiError::Database(_) => "database_error",
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            ApiError::InvalidField { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+
+    /// 把请求体提取器的错误转换过来，超出大小限制时返回413
+    pub fn from_payload(error: actix_web::Error) -> Self {
+        match error.as_error::<PayloadError>() {
+            Some(PayloadError::Overflow) => ApiError::PayloadTooLarge,
+            _ => ApiError::InvalidBody(format!("Unable to read the request body: {}", error)),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidField { message, .. } => write!(f, "{}", message),
+            ApiError::InvalidBody(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Forbidden(message)
+            | ApiError::UnsupportedMediaType(message) => {
+                write!(f, "{}", message)
+            },
+            ApiError::PayloadTooLarge => write!(f, "Request body is too large"),
+            ApiError::TooManyRequests(retry_after) => write!(f, "Too many requests, please retry after {} seconds", retry_after),
+            ApiError::Unavailable(_) => write!(f, "The database is busy, please retry later"),
+            ApiError::Database(_) => write!(f, "Database error"),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for ApiError {
+    fn from(error: diesel::r2d2::PoolError) -> Self {
+        ApiError::Unavailable(error.to_string())
+    }
+}
+
+impl From<diesel::result::Error> for ApiError {
+    fn from(error: diesel::result::Error) -> Self {
+        ApiError::Database(error.to_string())
+    }
+}
+
+/// `web::block`里的数据库或文件操作失败，原因只写日志
+impl<E: fmt::Debug + fmt::Display> From<BlockingError<E>> for ApiError {
+    fn from(error: BlockingError<E>) -> Self {
+        ApiError::Database(error.to_string())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidField { .. } | ApiError::InvalidBody(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Unavailable(e) | ApiError::Database(e) => tracing::error!(error = %e, code = self.code(), "database error"),
+            _ => {},
+        }
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            field: self.field(),
+            request_id: logging::current_request_id(),
+        })
+    }
+}
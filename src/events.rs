@@ -0,0 +1,135 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+use actix_web::rt;
+use diesel::{QueryResult, SqliteConnection};
+use futures::{Stream, channel::mpsc, stream};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use crate::models::{MessageJson, UserJson};
+use crate::webhooks;
+
+/// 留言板上发生的变化，推送给WebSocket等订阅者
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum BoardEvent {
+    #[serde(rename = "message.created")]
+    MessageCreated { message: MessageJson },
+    #[serde(rename = "message.deleted")]
+    MessageDeleted { message: MessageJson },
+    /// 留言的作者变了，目前只有数据库修复会这样
+    #[serde(rename = "message.updated")]
+    MessageUpdated { message: MessageJson },
+    #[serde(rename = "board.cleared")]
+    BoardCleared { deleted: usize },
+    #[serde(rename = "user.created")]
+    UserCreated { user: UserJson },
+}
+
+impl BoardEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BoardEvent::MessageCreated { .. } => "message.created",
+            BoardEvent::MessageDeleted { .. } => "message.deleted",
+            BoardEvent::MessageUpdated { .. } => "message.updated",
+            BoardEvent::BoardCleared { .. } => "board.cleared",
+            BoardEvent::UserCreated { .. } => "user.created",
+        }
+    }
+
+    /// 事件涉及的用户，清空留言板时为空
+    pub fn user(&self) -> Option<i32> {
+        match self {
+            BoardEvent::MessageCreated { message }
+            | BoardEvent::MessageDeleted { message }
+            | BoardEvent::MessageUpdated { message } => Some(message.user),
+            BoardEvent::UserCreated { user } => Some(user.id),
+            BoardEvent::BoardCleared { .. } => None,
+        }
+    }
+}
+
+/// 订阅哪些用户的事件，`users`为空表示全部。清空留言板的事件总会送达
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub users: Option<Vec<i32>>,
+}
+
+impl EventFilter {
+    fn accepts(&self, event: &BoardEvent) -> bool {
+        match (&self.users, event.user()) {
+            (Some(users), Some(user)) => users.contains(&user),
+            _ => true,
+        }
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    filter: EventFilter,
+    sender: mpsc::Sender<Arc<BoardEvent>>,
+}
+
+/// 把事件分发给所有订阅者。每个订阅者有自己的缓冲区，缓冲区满了说明对方读得太慢，
+/// 这时直接断开它（接收端读完已缓冲的事件后结束），不让它拖慢发布者和其他订阅者
+#[derive(Default)]
+pub struct EventHub {
+    next_id: AtomicU64,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+pub static HUB: Lazy<EventHub> = Lazy::new(EventHub::default);
+
+impl EventHub {
+    pub fn subscribe(&self, filter: EventFilter, buffer: usize) -> mpsc::Receiver<Arc<BoardEvent>> {
+        let (sender, receiver) = mpsc::channel(buffer.saturating_sub(1)); //`channel`的容量是参数加1
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().push(Subscriber { id, filter, sender });
+        receiver
+    }
+
+    pub fn publish(&self, event: BoardEvent) {
+        let event = Arc::new(event);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|subscriber| {
+            if !subscriber.filter.accepts(&event) {
+                return !subscriber.sender.is_closed();
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.is_full() {
+                        tracing::warn!(subscriber = subscriber.id, "event subscriber fell behind, disconnecting");
+                    }
+                    false
+                },
+            }
+        }); //接收端已经断开的订阅者在这里一并清理
+    }
+}
+
+/// 在修改留言板的事务里调用，为订阅了这个事件的webhook排队投递。投递记录和修改一起提交或回滚，
+/// 不会有修改了却没有通知、或者通知了却没有修改的情况
+pub fn record(db_connection: &SqliteConnection, event: &BoardEvent) -> QueryResult<usize> {
+    webhooks::enqueue(db_connection, event)
+}
+
+/// 事务提交后再发布到全局的`HUB`，订阅者收到事件时一定能查到对应的修改
+pub fn publish(event: BoardEvent) {
+    tracing::debug!(event = event.name(), "board event");
+    HUB.publish(event);
+}
+
+/// 一次修改产生多个事件时用，在事务里逐个`record`
+pub fn record_all(db_connection: &SqliteConnection, events: &[BoardEvent]) -> QueryResult<()> {
+    for event in events {
+        record(db_connection, event)?;
+    }
+    Ok(())
+}
+
+/// 实时连接的心跳，每隔`period`产生一次
+pub fn heartbeat(period: Duration) -> impl Stream<Item = ()> {
+    stream::unfold(rt::time::interval_at(rt::time::Instant::now() + period, period), |mut interval| async move {
+        interval.tick().await;
+        Some(((), interval))
+    })
+}
@@ -0,0 +1,230 @@
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use qstring::QString;
+use crate::Pool;
+use crate::board;
+use crate::error::ApiError;
+use crate::models::PostMessage;
+
+/// 每页的条目数
+pub const FEED_PAGE_SIZE: i64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "atom",
+            FeedFormat::Rss => "rss",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+}
+
+/// 一页订阅内容，`base_url`是不带查询参数的订阅地址
+#[derive(Debug)]
+pub struct FeedPage {
+    pub title: String,
+    pub base_url: String,
+    pub host: String,
+    pub page: i64,
+    pub has_next: bool,
+    pub updated: NaiveDateTime,
+    pub entries: Vec<(PostMessage, Option<String>)>,
+}
+
+impl FeedPage {
+    fn page_url(&self, page: i64) -> String {
+        match page {
+            1 => self.base_url.clone(),
+            page => format!("{}?page={}", self.base_url, page),
+        }
+    }
+
+    /// 第一页、上一页、下一页的链接，按RFC 5005的`rel`命名
+    fn paging_links(&self) -> Vec<(&'static str, String)> {
+        let mut links = vec![("self", self.page_url(self.page)), ("first", self.page_url(1))];
+        if self.page > 1 {
+            links.push(("previous", self.page_url(self.page - 1)));
+        }
+        if self.has_next {
+            links.push(("next", self.page_url(self.page + 1)));
+        }
+        links
+    }
+
+    fn entry_id(&self, item: &PostMessage) -> String {
+        format!("tag:{},2021:message:{}", self.host, item.id)
+    }
+}
+
+/// 转义XML中的特殊字符，并去掉XML 1.0不允许出现的控制字符
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {},
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn utc(time: &NaiveDateTime) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(*time, Utc)
+}
+
+fn author_name(name: &Option<String>) -> &str {
+    name.as_deref().unwrap_or("(deleted user)")
+}
+
+pub fn render_atom(page: &FeedPage) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <title>{}</title>\n", escape_xml(&page.title)));
+    xml.push_str(&format!("  <id>{}</id>\n", escape_xml(&page.base_url)));
+    xml.push_str(&format!("  <updated>{}</updated>\n", utc(&page.updated).to_rfc3339()));
+    xml.push_str("  <generator>backend-demo</generator>\n");
+    for (rel, href) in page.paging_links() {
+        xml.push_str(&format!("  <link rel=\"{}\" href=\"{}\"/>\n", rel, escape_xml(&href)));
+    }
+    for (item, name) in &page.entries {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>{}</id>\n", escape_xml(&page.entry_id(item))));
+        xml.push_str(&format!("    <title type=\"text\">{}</title>\n", escape_xml(&item.title)));
+        xml.push_str(&format!("    <author><name>{}</name></author>\n", escape_xml(author_name(name))));
+        xml.push_str(&format!("    <published>{}</published>\n", utc(&item.pub_date).to_rfc3339()));
+        xml.push_str(&format!("    <updated>{}</updated>\n", utc(&item.pub_date).to_rfc3339()));
+        xml.push_str(&format!("    <content type=\"text\">{}</content>\n", escape_xml(&item.content)));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+pub fn render_rss(page: &FeedPage) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+         <channel>\n",
+    );
+    xml.push_str(&format!("  <title>{}</title>\n", escape_xml(&page.title)));
+    xml.push_str(&format!("  <link>{}</link>\n", escape_xml(&page.base_url)));
+    xml.push_str(&format!("  <description>{}</description>\n", escape_xml(&page.title)));
+    xml.push_str(&format!("  <lastBuildDate>{}</lastBuildDate>\n", utc(&page.updated).to_rfc2822()));
+    xml.push_str("  <generator>backend-demo</generator>\n");
+    for (rel, href) in page.paging_links() {
+        xml.push_str(&format!("  <atom:link rel=\"{}\" href=\"{}\"/>\n", rel, escape_xml(&href)));
+    } //RSS没有分页，沿用Atom的链接
+    for (item, name) in &page.entries {
+        xml.push_str("  <item>\n");
+        xml.push_str(&format!("    <guid isPermaLink=\"false\">{}</guid>\n", escape_xml(&page.entry_id(item))));
+        xml.push_str(&format!("    <title>{}</title>\n", escape_xml(&item.title)));
+        xml.push_str(&format!("    <dc:creator>{}</dc:creator>\n", escape_xml(author_name(name))));
+        xml.push_str(&format!("    <pubDate>{}</pubDate>\n", utc(&item.pub_date).to_rfc2822()));
+        xml.push_str(&format!("    <description>{}</description>\n", escape_xml(&item.content)));
+        xml.push_str("  </item>\n");
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+/// 按`?page=`取出一页留言，`author`为空时是全站的订阅
+fn load_page(request: &HttpRequest, pool: &Pool, author: Option<&str>, format: FeedFormat) -> Result<FeedPage, ApiError> {
+    use crate::schema::board_revision::dsl::*;
+    let page = match QString::from(request.query_string()).get("page") {
+        Some(text) => text
+            .parse::<i64>()
+            .ok()
+            .filter(|page| *page >= 1 && *page <= i64::MAX / FEED_PAGE_SIZE) //计算偏移量时不能溢出
+            .ok_or_else(|| ApiError::invalid_field("page", format!("{} is not a page number", text)))?,
+        None => 1,
+    };
+    let db_connection = pool.get()?;
+    let (author_id, title, path) = match author {
+        Some(author) => {
+            let found = board::find_user(&db_connection, author)?
+                .ok_or_else(|| ApiError::NotFound(format!("User '{}' does not exist", author)))?;
+            let path = format!("/user/{}/feed.{}", percent_encode(author), format.extension());
+            (Some(found.id), format!("Messages by {}", author), path)
+        },
+        None => (None, String::from("Message board"), format!("/feed.{}", format.extension())),
+    };
+    let mut entries = board::latest_messages(&db_connection, author_id, FEED_PAGE_SIZE + 1, (page - 1) * FEED_PAGE_SIZE)?;
+    let has_next = entries.len() as i64 > FEED_PAGE_SIZE; //多取一条来判断有没有下一页
+    entries.truncate(FEED_PAGE_SIZE as usize);
+    let updated = board_revision.select(updated_at).first::<NaiveDateTime>(&db_connection)?;
+    let connection_info = request.connection_info();
+    Ok(FeedPage {
+        title,
+        base_url: format!("{}://{}{}", connection_info.scheme(), connection_info.host(), path),
+        host: host_name(connection_info.host()).to_string(),
+        page,
+        has_next,
+        updated,
+        entries,
+    })
+}
+
+/// 去掉端口，IPv6地址保留方括号
+fn host_name(authority: &str) -> &str {
+    match authority.find(']') {
+        Some(end) if authority.starts_with('[') => &authority[..=end],
+        _ => authority.split(':').next().unwrap_or(authority),
+    }
+}
+
+/// 用户名放进路径前要转义
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn respond(request: &HttpRequest, pool: &Pool, author: Option<&str>, format: FeedFormat) -> Result<HttpResponse, ApiError> {
+    let page = load_page(request, pool, author, format)?;
+    let body = match format {
+        FeedFormat::Atom => render_atom(&page),
+        FeedFormat::Rss => render_rss(&page),
+    };
+    Ok(HttpResponse::Ok().content_type(format.content_type()).body(body))
+}
+
+#[get("/feed.atom")]
+pub async fn board_atom(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    respond(&request, &pool, None, FeedFormat::Atom)
+}
+
+#[get("/feed.rss")]
+pub async fn board_rss(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    respond(&request, &pool, None, FeedFormat::Rss)
+}
+
+#[get("/user/{name}/feed.atom")]
+pub async fn user_atom(request: HttpRequest, name: web::Path<String>, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    respond(&request, &pool, Some(&name), FeedFormat::Atom)
+}
+
+#[get("/user/{name}/feed.rss")]
+pub async fn user_rss(request: HttpRequest, name: web::Path<String>, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    respond(&request, &pool, Some(&name), FeedFormat::Rss)
+}
@@ -0,0 +1,337 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, http::Method, rt, web::{self, Bytes}};
+use async_graphql::{
+    Context, EmptySubscription, ErrorExtensions, Object, Schema,
+    connection::{self, Connection, Edge},
+    dataloader::{DataLoader, Loader},
+    http::GraphiQLSource,
+    Name,
+    parser::{self, types::{ExecutableDocument, OperationType, Selection, SelectionSet}},
+};
+use diesel::{RunQueryDsl, prelude::*};
+use once_cell::sync::Lazy;
+use crate::Pool;
+use crate::board;
+use crate::config::{ApiConfig, ContentLimits};
+use crate::cors;
+use crate::error::ApiError;
+use crate::metrics;
+use crate::models::{PostMessage, PostUser, ReceiveMessageJson};
+use crate::operations;
+use crate::ratelimit::RateLimits;
+use crate::timezone::Zone;
+
+/// 没有`first`和`last`时每页的条数
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// `first`、`last`的上限
+const MAX_PAGE_SIZE: usize = 500;
+
+pub type BoardSchema = Schema<Query, Mutation, EmptySubscription>;
+
+/// 限制嵌套深度和复杂度，避免`messages { author { messages { ... } } }`这样的查询拖垮数据库
+pub static SCHEMA: Lazy<BoardSchema> = Lazy::new(|| {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .limit_depth(8)
+        .limit_complexity(5000)
+        .finish()
+});
+
+/// 每个请求的上下文
+struct Board {
+    pool: Pool,
+    limits: ContentLimits,
+    user: String, //发帖人，和REST接口一样取自`user`cookie
+    rate_limits: Option<web::Data<RateLimits>>,
+    client: String, //限流用的客户端IP
+}
+
+impl ErrorExtensions for ApiError {
+    fn extend(&self) -> async_graphql::Error {
+        if let ApiError::Database(detail) = self {
+            tracing::error!(error = %detail, "database error");
+        }
+        async_graphql::Error::new(self.to_string()).extend_with(|_, extensions| {
+            extensions.set("code", self.code());
+            if let Some(field) = self.field() {
+                extensions.set("field", field);
+            }
+        })
+    }
+}
+
+/// 按id批量查询作者。同一个请求里所有`Message.author`只查一次数据库
+pub struct AuthorLoader {
+    pool: Pool,
+}
+
+impl Loader<i32> for AuthorLoader {
+    type Value = PostUser;
+    type Error = Arc<ApiError>;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, PostUser>, Self::Error> {
+        use crate::schema::user::dsl::*;
+        let db_connection = self.pool.get().map_err(|e| Arc::new(ApiError::from(e)))?;
+        let users = metrics::time_query("load_authors", || user.filter(id.eq_any(keys)).load::<PostUser>(&db_connection))
+            .map_err(|e| Arc::new(ApiError::from(e)))?;
+        Ok(users.into_iter().map(|item| (item.id, item)).collect())
+    }
+}
+
+pub struct Message(PostMessage);
+
+#[Object]
+impl Message {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    /// 作者的id，作者已被删除时`author`为空
+    async fn user_id(&self) -> i32 {
+        self.0.user
+    }
+
+    async fn title(&self) -> &str {
+        &self.0.title
+    }
+
+    async fn content(&self) -> &str {
+        &self.0.content
+    }
+
+    /// RFC 3339格式的UTC时间
+    async fn pub_date(&self) -> String {
+        Zone::Utc.format(&self.0.pub_date)
+    }
+
+    async fn author(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<User>> {
+        let loader = ctx.data_unchecked::<DataLoader<AuthorLoader>>();
+        let author = loader.load_one(self.0.user).await.map_err(|e| e.extend())?;
+        Ok(author.map(User))
+    }
+}
+
+pub struct User(PostUser);
+
+#[Object]
+impl User {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn register_date(&self) -> String {
+        Zone::Utc.format(&self.0.register_date)
+    }
+
+    /// 这个用户的留言，按id顺序分页
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<i32, Message>> {
+        message_connection(ctx, Some(self.0.id), after, before, first, last).await
+    }
+}
+
+/// 按id取出一页留言。`first`从`after`之后往后取，`last`从`before`之前往前取，
+/// 多取一条来判断这一方向上还有没有下一页
+fn load_page(
+    pool: &Pool,
+    author: Option<i32>,
+    after: Option<i32>,
+    before: Option<i32>,
+    first: Option<usize>,
+    last: Option<usize>,
+) -> Result<(Vec<PostMessage>, bool, bool), ApiError> {
+    use crate::schema::message::dsl::*;
+    let db_connection = pool.get()?;
+    let mut query = message.into_boxed();
+    if let Some(author) = author {
+        query = query.filter(user.eq(author));
+    }
+    if let Some(after) = after {
+        query = query.filter(id.gt(after));
+    }
+    if let Some(before) = before {
+        query = query.filter(id.lt(before));
+    }
+    match (first, last) {
+        (None, Some(count)) => {
+            let mut page = metrics::time_query("list_messages", || query.order(id.desc()).limit(count as i64 + 1).load::<PostMessage>(&db_connection))?;
+            let has_previous = page.len() > count;
+            page.truncate(count);
+            page.reverse();
+            Ok((page, has_previous, before.is_some()))
+        },
+        (first, _) => {
+            let count = first.unwrap_or(DEFAULT_PAGE_SIZE);
+            let mut page = metrics::time_query("list_messages", || query.order(id).limit(count as i64 + 1).load::<PostMessage>(&db_connection))?;
+            let has_next = page.len() > count;
+            page.truncate(count);
+            Ok((page, after.is_some(), has_next))
+        },
+    }
+}
+
+async fn message_connection(
+    ctx: &Context<'_>,
+    author: Option<i32>,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> async_graphql::Result<Connection<i32, Message>> {
+    let board = ctx.data_unchecked::<Board>();
+    connection::query(after, before, first, last, |after: Option<i32>, before: Option<i32>, first, last| async move {
+        if first.is_some() && last.is_some() {
+            return Err(ApiError::invalid_field("last", "'first' and 'last' cannot be used together").extend());
+        }
+        if first.or(last).is_some_and(|count| count > MAX_PAGE_SIZE) {
+            let field = if first.is_some() { "first" } else { "last" };
+            return Err(ApiError::invalid_field(field, format!("At most {} items per page", MAX_PAGE_SIZE)).extend());
+        }
+        let (page, has_previous, has_next) = load_page(&board.pool, author, after, before, first, last).map_err(|e| e.extend())?;
+        let mut connection = Connection::new(has_previous, has_next);
+        connection.edges.extend(page.into_iter().map(|item| Edge::new(item.id, Message(item))));
+        Ok::<_, async_graphql::Error>(connection)
+    })
+    .await
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// 按id顺序分页列出留言，`user`为用户名时只列出这个用户的留言
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        user: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<i32, Message>> {
+        let author = match user {
+            Some(name) => match find_user(ctx, &name)? {
+                Some(found) => Some(found.id),
+                None => return Ok(Connection::new(false, false)),
+            },
+            None => None,
+        };
+        message_connection(ctx, author, after, before, first, last).await
+    }
+
+    async fn message(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<Option<Message>> {
+        use crate::schema::message::dsl::message;
+        let board = ctx.data_unchecked::<Board>();
+        let db_connection = board.pool.get().map_err(|e| ApiError::from(e).extend())?;
+        let found = metrics::time_query("find_message", || message.find(id).first::<PostMessage>(&db_connection).optional())
+            .map_err(|e| ApiError::from(e).extend())?;
+        Ok(found.map(Message))
+    }
+
+    async fn user(&self, ctx: &Context<'_>, name: String) -> async_graphql::Result<Option<User>> {
+        Ok(find_user(ctx, &name)?.map(User))
+    }
+}
+
+fn find_user(ctx: &Context<'_>, name: &str) -> async_graphql::Result<Option<PostUser>> {
+    let board = ctx.data_unchecked::<Board>();
+    let db_connection = board.pool.get().map_err(|e| ApiError::from(e).extend())?;
+    metrics::time_query("find_user", || board::find_user(&db_connection, name)).map_err(|e| ApiError::from(e).extend())
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// 发表留言，和`POST /api/v2/messages`的检查相同
+    async fn post_message(&self, ctx: &Context<'_>, title: String, content: String) -> async_graphql::Result<Message> {
+        let board = ctx.data_unchecked::<Board>();
+        if let Some(rate_limits) = &board.rate_limits {
+            let decision = rate_limits.check(&Method::POST, "/api/v2/messages", &board.client);
+            if let Some(decision) = decision.filter(|decision| !decision.allowed) {
+                return Err(ApiError::TooManyRequests(decision.retry_after_secs).extend());
+            }
+        } //每个postMessage都按发帖的配额计数，一个请求里用别名发多条也一样
+        let post_data = ReceiveMessageJson { title, content };
+        let saved = operations::post_message(&board.pool, &board.limits, &board.user, &post_data).map_err(|e| e.extend())?;
+        Ok(Message(saved))
+    }
+
+    /// 删除一条留言，留言不存在时返回`false`
+    async fn delete_message(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<bool> {
+        let board = ctx.data_unchecked::<Board>();
+        operations::delete_message(&board.pool, id).map_err(|e| e.extend())
+    }
+}
+
+/// 和管理接口一样不允许跨域调用的变更
+const ADMIN_MUTATIONS: [&str; 1] = ["deleteMessage"];
+
+/// 请求里的变更有没有选择`ADMIN_MUTATIONS`里的字段，通过片段选择的也算。解析失败时交给执行阶段报错
+fn has_admin_mutation(query: &str) -> bool {
+    fn selects(selection_set: &SelectionSet, document: &ExecutableDocument, visited: &mut HashSet<Name>) -> bool {
+        selection_set.items.iter().any(|item| match &item.node {
+            Selection::Field(field) => ADMIN_MUTATIONS.contains(&field.node.name.node.as_str()),
+            Selection::InlineFragment(fragment) => selects(&fragment.node.selection_set.node, document, visited),
+            Selection::FragmentSpread(spread) => {
+                let name = &spread.node.fragment_name.node;
+                visited.insert(name.clone()) //片段互相引用时只看一次
+                    && document.fragments.get(name).is_some_and(|fragment| selects(&fragment.node.selection_set.node, document, visited))
+            },
+        })
+    }
+    parser::parse_query(query).is_ok_and(|document| {
+        document.operations.iter().any(|(_, operation)| {
+            operation.node.ty == OperationType::Mutation
+                && selects(&operation.node.selection_set.node, &document, &mut HashSet::new())
+        })
+    })
+}
+
+/// 执行一个GraphQL请求。出错时也返回200，错误在响应的`errors`里；只有请求体不是合法的GraphQL请求时返回400。
+/// 只接受JSON，这样跨域请求一定要先预检，只有CORS允许的来源才能发帖；跨域的删除和管理接口一样直接拒绝
+pub async fn execute(
+    request_raw: Result<Bytes, Error>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    limits: web::Data<ContentLimits>,
+) -> Result<HttpResponse, ApiError> {
+    match request.mime_type() {
+        Ok(Some(mime)) if mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON) => {},
+        Ok(_) => return Err(ApiError::UnsupportedMediaType(String::from("Content-Type must be application/json"))),
+        Err(_) => return Err(ApiError::InvalidBody(String::from("Malformed Content-Type header"))),
+    }
+    let request_raw = request_raw.map_err(ApiError::from_payload)?;
+    let query = serde_json::from_slice::<async_graphql::Request>(&request_raw).map_err(|e| {
+        ApiError::InvalidBody(format!("Invalid GraphQL request at line {} column {}: {}", e.line(), e.column(), e))
+    })?;
+    if cors::is_cross_origin(&request) && has_admin_mutation(&query.query) {
+        return Err(ApiError::Forbidden(String::from("Cross-origin deleteMessage is not allowed")));
+    }
+    let pool = pool.get_ref().clone();
+    let loader = DataLoader::new(AuthorLoader { pool: pool.clone() }, rt::spawn);
+    let rate_limits = request.app_data::<web::Data<RateLimits>>().cloned();
+    let client = rate_limits.as_ref().map(|rate_limits| rate_limits.client_key(&request)).unwrap_or_default();
+    let board = Board { pool, limits: *limits.get_ref(), user: operations::cookie_user(&request), rate_limits, client };
+    let response = SCHEMA.execute(query.data(board).data(loader)).await;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// 开发时调试用的GraphiQL页面，`api.graphiql`关闭时返回404
+pub async fn graphiql(api: ApiConfig) -> Result<HttpResponse, ApiError> {
+    if !api.graphiql {
+        return Err(ApiError::NotFound(String::from("GraphiQL is disabled, set api.graphiql = true to enable it")));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/api/graphql").finish()))
+}
@@ -0,0 +1,175 @@
+use std::time::{Duration, Instant};
+use actix_web::{HttpResponse, Responder, get, web};
+use diesel::{RunQueryDsl, prelude::*, sql_query, sql_types::{Integer, Nullable, Text}};
+use serde::Serialize;
+use serde_json::json;
+use crate::Pool;
+
+/// 就绪检查取连接最多等这么久，不能让探针跟着连接池的超时一起卡住
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, QueryableByName)]
+struct OneRow {
+    #[sql_type = "Integer"]
+    one: i32,
+}
+
+#[derive(Debug, QueryableByName)]
+struct VersionRow {
+    #[sql_type = "Nullable<Text>"]
+    version: Option<String>,
+}
+
+#[derive(Debug, QueryableByName)]
+struct JournalModeRow {
+    #[sql_type = "Text"]
+    journal_mode: String,
+}
+
+#[derive(Debug, QueryableByName)]
+struct DatabaseRow {
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Text"]
+    file: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn passed() -> Self {
+        Check { ok: true, error: None }
+    }
+
+    fn failed(error: impl ToString) -> Self {
+        Check { ok: false, error: Some(error.to_string()) }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    #[serde(flatten)]
+    pub check: Check,
+    pub applied: Option<String>,
+    pub expected: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalStatus {
+    #[serde(flatten)]
+    pub check: Check,
+    pub journal_mode: Option<String>,
+    pub wal_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub pool: Check,
+    pub checkout_ms: f64,
+    pub query: Check,
+    pub migrations: MigrationStatus,
+    pub wal: WalStatus,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.pool.ok && self.query.ok && self.migrations.check.ok && self.wal.check.ok
+    }
+}
+
+/// 依次检查：能否取到连接、能否执行查询、迁移是否最新、WAL的状态。
+/// 取不到连接时后面的检查都记为失败
+pub fn check_readiness(pool: &Pool) -> Readiness {
+    let started = Instant::now();
+    let checkout = pool.get_timeout(CHECKOUT_TIMEOUT);
+    let checkout_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let db_connection = match checkout {
+        Ok(db_connection) => db_connection,
+        Err(e) => {
+            return Readiness {
+                pool: Check::failed(&e),
+                checkout_ms,
+                query: Check::failed("no connection"),
+                migrations: MigrationStatus { check: Check::failed("no connection"), applied: None, expected: crate::SCHEMA_VERSION },
+                wal: WalStatus { check: Check::failed("no connection"), journal_mode: None, wal_bytes: None },
+            };
+        }
+    };
+    let query = match sql_query("SELECT 1 AS one").get_result::<OneRow>(&db_connection) {
+        Ok(row) if row.one == 1 => Check::passed(),
+        Ok(row) => Check::failed(format!("SELECT 1 returned {}", row.one)),
+        Err(e) => Check::failed(e),
+    };
+    Readiness {
+        pool: Check::passed(),
+        checkout_ms,
+        query,
+        migrations: migration_status(&db_connection),
+        wal: wal_status(&db_connection),
+    }
+}
+
+/// 最新一次执行过的迁移要和程序内嵌的最新迁移一致
+fn migration_status(db_connection: &SqliteConnection) -> MigrationStatus {
+    let expected = crate::SCHEMA_VERSION;
+    match sql_query("SELECT MAX(version) AS version FROM __diesel_schema_migrations").get_result::<VersionRow>(db_connection) {
+        Ok(VersionRow { version: Some(applied) }) if applied == expected => {
+            MigrationStatus { check: Check::passed(), applied: Some(applied), expected }
+        },
+        Ok(VersionRow { version: Some(applied) }) if applied.as_str() < expected => MigrationStatus {
+            check: Check::failed("migrations are pending; run 'backend-demo migrate'"),
+            applied: Some(applied),
+            expected,
+        },
+        Ok(VersionRow { version: Some(applied) }) => MigrationStatus {
+            check: Check::failed("the database was migrated by a newer version"),
+            applied: Some(applied),
+            expected,
+        },
+        Ok(VersionRow { version: None }) => {
+            MigrationStatus { check: Check::failed("no migrations have been run"), applied: None, expected }
+        },
+        Err(e) => MigrationStatus { check: Check::failed(e), applied: None, expected },
+    }
+}
+
+/// 报告日志模式和`-wal`文件的大小，WAL文件过大说明检查点一直没能完成
+fn wal_status(db_connection: &SqliteConnection) -> WalStatus {
+    let journal_mode = match sql_query("PRAGMA journal_mode").get_result::<JournalModeRow>(db_connection) {
+        Ok(row) => row.journal_mode,
+        Err(e) => return WalStatus { check: Check::failed(e), journal_mode: None, wal_bytes: None },
+    };
+    let wal_bytes = if journal_mode == "wal" {
+        sql_query("PRAGMA database_list")
+            .load::<DatabaseRow>(db_connection)
+            .ok()
+            .and_then(|rows| rows.into_iter().find(|row| row.name == "main"))
+            .filter(|row| !row.file.is_empty())
+            .map(|row| std::fs::metadata(format!("{}-wal", row.file)).map(|m| m.len()).unwrap_or(0))
+    } else {
+        None
+    };
+    WalStatus { check: Check::passed(), journal_mode: Some(journal_mode), wal_bytes }
+}
+
+/// 存活检查：只要进程还能处理请求就返回200
+#[get("/healthz")]
+pub async fn liveness() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// 就绪检查：任何一项失败都返回503和详细结果
+#[get("/readyz")]
+pub async fn readiness(pool: web::Data<Pool>) -> impl Responder {
+    let pool = pool.get_ref().clone();
+    match web::block(move || Ok::<_, ()>(check_readiness(&pool))).await {
+        Ok(report) if report.is_ready() => HttpResponse::Ok().json(json!({ "status": "ready", "checks": report })),
+        Ok(report) => HttpResponse::ServiceUnavailable().json(json!({ "status": "not ready", "checks": report })),
+        Err(_) => HttpResponse::ServiceUnavailable().json(json!({ "status": "not ready", "error": "readiness check was canceled" })),
+    }
+}
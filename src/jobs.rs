@@ -0,0 +1,44 @@
+use std::{future::Future, time::Duration};
+use actix_web::rt;
+use futures::{channel::oneshot, future::{self, Either}};
+
+/// 服务器启动的后台定时任务，停止服务器时用`stop`逐个停掉
+#[derive(Default)]
+pub struct Jobs {
+    running: Vec<(&'static str, oneshot::Sender<()>, oneshot::Receiver<()>)>,
+}
+
+impl Jobs {
+    /// 在actix运行时里从`start`开始每隔`period`执行一次`tick`。
+    /// `tick`持有的资源（比如连接池）在任务停止时释放
+    pub fn spawn<F, T>(&mut self, name: &'static str, start: rt::time::Instant, period: Duration, mut tick: F)
+    where
+        F: FnMut() -> T + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        let (stop, mut stopping) = oneshot::channel::<()>();
+        let (finished, done) = oneshot::channel::<()>();
+        rt::spawn(async move {
+            let mut ticker = rt::time::interval_at(start, period);
+            while let Either::Left(_) = future::select(Box::pin(ticker.tick()), &mut stopping).await {
+                tick().await; //停止时正在执行的这一轮不会被打断
+            }
+            drop(tick);
+            let _ = finished.send(());
+        });
+        self.running.push((name, stop, done));
+    }
+
+    /// 通知所有任务停止，等它们都退出后返回
+    pub async fn stop(self) {
+        let mut waiting = Vec::new();
+        for (name, stop, done) in self.running {
+            let _ = stop.send(());
+            waiting.push((name, done));
+        }
+        for (name, done) in waiting {
+            let _ = done.await;
+            tracing::info!(job = name, "background job stopped");
+        }
+    }
+}
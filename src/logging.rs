@@ -0,0 +1,156 @@
+use std::{cell::RefCell, pin::Pin, task::{Context, Poll}, time::Instant};
+use actix_web::{Error, HttpMessage, dev::{Service, ServiceRequest, ServiceResponse, Transform}, http::{HeaderName, HeaderValue}};
+use futures::future::{Future, Ready, ok};
+use tracing::{Instrument, field::Empty};
+use tracing_subscriber::EnvFilter;
+use crate::config::{LogConfig, LogFormat};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// 按配置初始化全局的日志输出。日志写到标准错误，不会和命令行的输出混在一起
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_new(&config.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match config.format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to initialize logging: {}", e);
+    }
+}
+
+/// 每个请求的id，放在请求的extensions里，其他地方可以用`request.extensions().get::<RequestId>()`取到
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// 沿用客户端或代理传来的`X-Request-Id`，没有或不合法时生成一个新的
+    fn from_request(request: &ServiceRequest) -> Self {
+        let incoming = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= 64)
+            .filter(|value| value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'));
+        match incoming {
+            Some(value) => RequestId(String::from(value)),
+            None => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// 正在处理的请求的id。只在`RequestTracing`处理请求的过程中有值，生成错误响应时用
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// 每次poll内层future前记下请求id，poll完恢复原来的值。
+/// 同一个worker线程上交替处理多个请求，所以不能只在请求开始时设置一次
+struct WithRequestId<F> {
+    request_id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let previous = CURRENT_REQUEST_ID.with(|current| current.replace(Some(this.request_id.clone())));
+        let result = this.future.as_mut().poll(cx);
+        CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+}
+
+/// 为每个请求创建一个span，记录方法、路径、请求id、状态码和耗时。
+/// 处理函数确定了用户之后可以用`tracing::Span::current().record("user", ...)`补上用户名
+pub struct RequestTracing;
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let request_id = RequestId::from_request(&request);
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            path = %request.path(),
+            request_id = %request_id.0,
+            user = Empty,
+            status = Empty,
+            latency_ms = Empty,
+        );
+        request.extensions_mut().insert(request_id.clone());
+        let started = Instant::now();
+        let future = {
+            let _entered = span.enter();
+            self.service.call(request)
+        };
+        Box::pin(async move {
+            let result = WithRequestId { request_id: request_id.0.clone(), future: Box::pin(future) }
+                .instrument(span.clone())
+                .await;
+            let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            span.record("status", &status.as_u16());
+            span.record("latency_ms", &latency_ms);
+            let _entered = span.enter();
+            if status.is_server_error() {
+                tracing::error!("request failed");
+            } else {
+                tracing::info!("request completed");
+            }
+            result.map(|mut response| {
+                if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                    response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                response
+            })
+        })
+    }
+}
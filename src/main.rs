@@ -1,41 +1,275 @@
+#![allow(non_local_definitions)] // diesel 1.4的derive宏会产生这个警告
+
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 
+mod admin;
+mod backup;
+mod board;
+mod cli;
+mod cors;
+mod csv;
+mod error;
+mod events;
+mod feed;
+mod graphql;
+mod health;
+mod jobs;
+mod logging;
+mod maintenance;
+mod metrics;
+mod openapi;
+mod retention;
+mod seed;
+mod sse;
+mod timezone;
+mod tls;
+mod transfer;
+mod v2;
+mod versioning;
+mod webhooks;
+mod ws;
 mod operations;
+mod ratelimit;
 mod schema;
 mod models;
 mod server_test;
 mod config;
 
-use actix_web::{App, HttpServer, web};
+use actix_web::{App, HttpServer, Resource, dev::HttpServiceFactory, web};
+use clap::Parser;
 use diesel::{r2d2::{self, ConnectionManager}, sqlite::SqliteConnection};
 use dotenv::dotenv;
-use crate::config::ConnectionOptions;
+use crate::cli::{Cli, Command, ConfigCommand};
+use crate::config::Config;
 
 pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
+embed_migrations!();
+
+/// 最新一个迁移的版本号，添加迁移时要一起更新；`/readyz`据此判断数据库是否已迁移到最新
+pub const SCHEMA_VERSION: &str = "20261019000003";
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let database_url = std::env::var("DATABASE_URL")
-        .expect("Unable to locate the database.\nTry setting the 'DATABASE_URL' variable.");
-    let database = Pool::builder()
-        .max_size(16)
-        .connection_customizer(Box::new(ConnectionOptions {
-            enable_wal: true,
-            enable_foreign_keys: true,
-            busy_timeout: Some(std::time::Duration::from_secs(30)),
-        }))
-        .build(ConnectionManager::<SqliteConnection>::new(database_url))
-        .expect("Unable to open the database.");
-    HttpServer::new(move || {
+    let cli = Cli::parse();
+    if let Some(Command::Config { command: ConfigCommand::Print }) = cli.command {
+        return cli::print_config(cli.config.as_deref(), &cli.overrides);
+    }
+    let config = match Config::load(cli.config.as_deref(), &cli.overrides) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            std::process::exit(2);
+        }
+    }; //启动前检查配置，有问题时全部列出后退出
+    logging::init(&config.log);
+    match &cli.command {
+        Some(Command::Backup { path }) => return cli::backup_database(&config, path),
+        Some(Command::Restore { path }) => return cli::restore_database(&config, path),
+        _ => {},
+    } //备份和恢复直接操作数据库文件，不需要连接池
+    let database = config.build_pool().expect("Unable to open the database.");
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config, database).await,
+        Command::Export { path } => cli::export_board(&database, &path),
+        Command::Import { path, dry_run } => cli::import_board(&database, &path, dry_run, &config.limits),
+        Command::MigrateTimestamps { timezone } => {
+            cli::migrate_timestamps(&database, timezone.as_deref().unwrap_or(&config.timestamps.source_timezone))
+        },
+        Command::Check { fix, vacuum } => cli::check_database(&database, fix, vacuum),
+        Command::Migrate => cli::run_migrations(&database, config.sqlite.foreign_keys),
+        Command::Seed => cli::seed_board(&database),
+        Command::ClearMessages => cli::clear_messages(&database),
+        Command::User { command } => cli::manage_users(&database, command, &config.limits),
+        Command::Message { command } => cli::manage_messages(&database, command),
+        Command::Backup { .. } | Command::Restore { .. } | Command::Config { .. } => unreachable!(),
+    }
+}
+
+/// 路由表中的一项：路径和注册它的函数
+pub type Route = (&'static str, Box<dyn FnOnce(&mut web::ServiceConfig)>);
+
+/// 用`#[get(..)]`等宏定义的接口，`path`要和宏里写的一致
+fn service<F: HttpServiceFactory + 'static>(path: &'static str, factory: F) -> Route {
+    (path, Box::new(move |routes: &mut web::ServiceConfig| { routes.service(factory); }))
+}
+
+/// 用`web::resource(path)`注册的接口
+fn resource<F, B>(path: &'static str, build: B) -> Route
+where
+    F: HttpServiceFactory + 'static,
+    B: FnOnce(Resource) -> F + 'static,
+{
+    (path, Box::new(move |routes: &mut web::ServiceConfig| { routes.service(build(web::resource(path))); }))
+}
+
+/// 所有接口。`register_routes`只按这张表注册，OpenAPI文档里的路径要和这里一致，
+/// `test_openapi_matches_routes`会检查。不带版本号的`/api/message`和`/api/clearmessage`是v1的别名
+pub fn route_table(limits: config::ContentLimits, api: config::ApiConfig) -> Vec<Route> {
+    let v1 = move |successor| versioning::V1 { successor, django_compat: api.django_compat };
+    vec![
+        service("/metrics", metrics::export_metrics),
+        service("/healthz", health::liveness),
+        service("/readyz", health::readiness),
+        service("/api/openapi.json", openapi::openapi_json),
+        service("/api/docs", openapi::docs),
+        service("/feed.atom", feed::board_atom),
+        service("/feed.rss", feed::board_rss),
+        service("/user/{name}/feed.atom", feed::user_atom),
+        service("/user/{name}/feed.rss", feed::user_rss),
+        resource("/api/message", move |resource| {
+            resource
+                .wrap(v1("/api/v2/messages"))
+                .app_data(web::PayloadConfig::new(limits.message_payload_bytes))
+                .route(web::get().to(operations::get_message))
+                .route(web::post().to(operations::get_post_message))
+        }),
+        resource("/api/v1/message", move |resource| {
+            resource
+                .wrap(v1("/api/v2/messages"))
+                .app_data(web::PayloadConfig::new(limits.message_payload_bytes))
+                .route(web::get().to(operations::get_message))
+                .route(web::post().to(operations::get_post_message))
+        }),
+        resource("/api/clearmessage", move |resource| {
+            resource
+                .wrap(v1("/api/v2/messages"))
+                .route(web::get().to(operations::clear_message))
+        }),
+        resource("/api/v1/clearmessage", move |resource| {
+            resource
+                .wrap(v1("/api/v2/messages"))
+                .route(web::get().to(operations::clear_message))
+        }),
+        resource("/api/v2/messages", move |resource| {
+            resource
+                .app_data(web::PayloadConfig::new(limits.message_payload_bytes))
+                .route(web::get().to(v2::list_messages))
+                .route(web::post().to(v2::create_message))
+                .route(web::delete().to(v2::delete_messages))
+        }),
+        resource("/api/graphql", move |resource| {
+            resource
+                .app_data(web::PayloadConfig::new(limits.message_payload_bytes))
+                .route(web::post().to(graphql::execute))
+                .route(web::get().to(move || graphql::graphiql(api)))
+        }),
+        resource("/api/message/stream", move |resource| {
+            resource
+                .data(api)
+                .route(web::get().to(sse::stream))
+        }),
+        resource("/api/ws", move |resource| {
+            resource
+                .data(api)
+                .route(web::get().to(ws::connect))
+        }),
+        service("/api/admin/backup", admin::create_backup),
+        service("/api/admin/export", admin::export_board),
+        service("/api/admin/retention", admin::run_retention),
+        service("/api/admin/maintenance", admin::run_maintenance),
+        service("/api/admin/webhooks", webhooks::create_webhook),
+        service("/api/admin/webhooks", webhooks::list_webhooks),
+        service("/api/admin/webhooks/{id}", webhooks::delete_webhook),
+        service("/api/admin/webhooks/{id}/deliveries", webhooks::list_deliveries),
+        resource("/api/admin/import", move |resource| {
+            resource
+                .app_data(web::PayloadConfig::new(limits.import_payload_bytes))
+                .route(web::post().to(admin::import_board))
+        }),
+    ]
+}
+
+/// 注册`route_table`里的所有接口
+pub fn register_routes(routes: &mut web::ServiceConfig, limits: config::ContentLimits, api: config::ApiConfig) {
+    for (_, register) in route_table(limits, api) {
+        register(routes);
+    }
+}
+
+async fn serve(config: Config, database: Pool) -> std::io::Result<()> {
+    if let Ok(true) = database.get().map_err(|e| e.to_string())
+        .and_then(|db_connection| timezone::conversion_pending(&db_connection).map_err(|e| e.to_string())) {
+            tracing::error!("stored timestamps are still in local time. Run 'backend-demo migrate-timestamps <timezone>' before serving.");
+            return Err(std::io::Error::other("timestamps have not been converted to UTC"));
+        } //新留言按UTC保存，如果先启动再转换，这些留言的时间会被再平移一次
+    let backup_options = config.backup_options();
+    let retention_options = config.retention_options();
+    let limits = config.limits;
+    let api = config.api;
+    let mut jobs = jobs::Jobs::default();
+    backup::spawn_periodic_backups(&mut jobs, backup_options.clone());
+    retention::spawn_retention_job(&mut jobs, database.clone(), retention_options.clone());
+    maintenance::spawn_maintenance_job(&mut jobs, database.clone(), config.maintenance_options());
+    webhooks::spawn_webhook_worker(&mut jobs, database.clone(), config.webhook_options());
+    let tls_options = config.tls_options();
+    let certificates = match &tls_options {
+        Some(options) => Some(
+            tls::ReloadableCert::load(&options.cert, &options.key)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        ),
+        None => None,
+    }; //证书有问题时直接退出，不要退回到HTTP
+    let rate_limits = std::sync::Arc::new(ratelimit::RateLimits::new(&config.rate_limit)); //所有worker共用同一组令牌桶
+    let cors_policies = std::sync::Arc::new(cors::CorsPolicies::new(&config.cors));
+    let pool = database.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(ratelimit::RateLimiting(rate_limits.clone()))
+            .app_data(web::Data::from(rate_limits.clone())) //GraphQL的postMessage按发帖的配额计数
+            .wrap(cors::Cors(cors_policies.clone()))
+            .wrap(metrics::RequestMetrics)
+            .wrap(logging::RequestTracing)
             .data(database.clone())
-            .service(operations::get_message)
-            .route("/api/message", web::post().to(operations::get_post_message))
-            .service(operations::clear_message)
-    })
-    .bind("127.0.0.1:8000")?
-    .run()
-    .await
-}
\ No newline at end of file
+            .data(backup_options.clone())
+            .data(retention_options.clone())
+            .data(limits)
+            .configure(|routes| register_routes(routes, limits, api))
+    });
+    server = server.shutdown_timeout(config.server.shutdown_timeout_secs);
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    for address in &config.server.listen {
+        server = match &certificates {
+            Some(certificates) => server.bind_rustls(address, tls::server_config(certificates.clone()))?,
+            None => server.bind(address)?,
+        };
+    }
+    let https = certificates.is_some();
+    if let Some(certificates) = certificates {
+        tls::spawn_reload_on_sighup(certificates);
+    }
+    tracing::info!(listen = ?config.server.listen, https, "server started");
+    let running = server.run();
+    match tls_options.filter(|options| !options.redirect_listen.is_empty()) {
+        Some(options) => {
+            let https_port = tls::HttpsPort(options.https_port);
+            let mut redirect = HttpServer::new(move || {
+                App::new()
+                    .data(https_port)
+                    .default_service(web::route().to(tls::redirect_to_https))
+            })
+            .workers(1);
+            for address in &options.redirect_listen {
+                redirect = redirect.bind(address)?;
+            }
+            tracing::info!(listen = ?options.redirect_listen, "redirecting HTTP to HTTPS");
+            futures::future::try_join(running, redirect.run()).await?;
+        },
+        None => running.await?,
+    } //收到SIGTERM后不再接受新连接，等处理中的请求完成或超时后返回
+    tracing::info!("requests drained, stopping background jobs");
+    jobs.stop().await;
+    tracing::info!("background jobs stopped, checkpointing the database");
+    maintenance::final_checkpoint(&pool); //worker退出时已经丢掉了各自的连接池，现在只剩这一个
+    drop(pool);
+    tracing::info!("database pool closed");
+    Ok(())
+}
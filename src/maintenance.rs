@@ -0,0 +1,210 @@
+use actix_web::{rt, web};
+use diesel::{RunQueryDsl, connection::SimpleConnection, prelude::*, sql_query, sql_types::{BigInt, Integer, Nullable, Text}};
+use serde::Serialize;
+use crate::{Pool, board};
+use crate::config::MaintenanceOptions;
+use crate::jobs::Jobs;
+use crate::events::{self, BoardEvent};
+use crate::models::{MessageJson, PostMessage, PostUser, UserJson};
+
+#[derive(Debug, QueryableByName)]
+struct IntegrityRow {
+    #[sql_type = "Text"]
+    integrity_check: String,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct ForeignKeyViolation {
+    #[sql_type = "Text"]
+    pub table: String,
+    #[sql_type = "Nullable<BigInt>"]
+    pub rowid: Option<i64>,
+    #[sql_type = "Text"]
+    pub parent: String,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+pub struct CheckpointResult {
+    #[sql_type = "Integer"]
+    pub busy: i32,
+    #[sql_type = "Integer"]
+    pub log: i32,
+    #[sql_type = "Integer"]
+    pub checkpointed: i32,
+}
+
+/// 同名的多个用户
+#[derive(Debug, Serialize)]
+pub struct DuplicateUser {
+    pub name: String,
+    pub ids: Vec<i32>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct HealthReport {
+    pub integrity: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+    pub orphaned_messages: Vec<i32>,
+    pub duplicate_users: Vec<DuplicateUser>,
+    pub fixed: Option<FixReport>,
+    pub checkpoint: Option<CheckpointResult>,
+    pub vacuumed: bool,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.integrity == ["ok"]
+            && self.foreign_key_violations.is_empty()
+            && self.orphaned_messages.is_empty()
+            && self.duplicate_users.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FixReport {
+    pub reassigned_messages: usize,
+    pub merged_users: usize,
+}
+
+/// 检查数据库：完整性、外键、作者不存在的留言和同名用户
+pub fn check(db_connection: &SqliteConnection) -> QueryResult<HealthReport> {
+    let integrity = sql_query("PRAGMA integrity_check")
+        .load::<IntegrityRow>(db_connection)?
+        .into_iter()
+        .map(|row| row.integrity_check)
+        .collect();
+    let foreign_key_violations = sql_query("PRAGMA foreign_key_check").load::<ForeignKeyViolation>(db_connection)?;
+    Ok(HealthReport {
+        integrity,
+        foreign_key_violations,
+        orphaned_messages: orphaned_messages(db_connection)?,
+        duplicate_users: duplicate_users(db_connection)?,
+        ..Default::default()
+    })
+}
+
+fn orphaned_messages(db_connection: &SqliteConnection) -> QueryResult<Vec<i32>> {
+    use crate::schema::{message, user};
+    message::table
+        .select(message::id)
+        .filter(message::user.ne_all(user::table.select(user::id)))
+        .order(message::id)
+        .load(db_connection)
+}
+
+fn duplicate_users(db_connection: &SqliteConnection) -> QueryResult<Vec<DuplicateUser>> {
+    use crate::schema::user::dsl::*;
+    let mut duplicates: Vec<DuplicateUser> = Vec::new();
+    for item in user.order((name, id)).load::<PostUser>(db_connection)? {
+        match duplicates.last_mut() {
+            Some(last) if last.name == item.name => last.ids.push(item.id),
+            _ => duplicates.push(DuplicateUser { name: item.name, ids: vec![item.id] }),
+        }
+    }
+    duplicates.retain(|duplicate| duplicate.ids.len() > 1);
+    Ok(duplicates)
+}
+
+/// 修复`check`发现的问题：同名用户合并到id最小的那个，
+/// 作者不存在的留言改为由`Unknown`用户发表（就像没有带cookie时那样）。
+/// 改了作者的留言产生`MessageUpdated`事件，新建`Unknown`用户时产生`UserCreated`事件
+pub fn fix(db_connection: &SqliteConnection) -> QueryResult<FixReport> {
+    use crate::schema::{message, user};
+    let (report, pending) = db_connection.immediate_transaction::<_, diesel::result::Error, _>(|| {
+        let mut report = FixReport::default();
+        let mut pending = Vec::new();
+        let mut reassigned = Vec::new();
+        for duplicate in duplicate_users(db_connection)? {
+            let (keep, merged) = duplicate.ids.split_first().expect("duplicates have at least two ids");
+            reassigned.extend(message::table.filter(message::user.eq_any(merged)).select(message::id).load::<i32>(db_connection)?);
+            report.reassigned_messages += diesel::update(message::table.filter(message::user.eq_any(merged)))
+                .set(message::user.eq(keep))
+                .execute(db_connection)?;
+            report.merged_users += diesel::delete(user::table.filter(user::id.eq_any(merged)))
+                .execute(db_connection)?;
+        }
+        let orphans = orphaned_messages(db_connection)?;
+        if !orphans.is_empty() {
+            let unknown = match user::table.filter(user::name.eq("Unknown")).first::<PostUser>(db_connection).optional()? {
+                Some(unknown) => unknown.id,
+                None => {
+                    let unknown = board::insert_user(db_connection, "Unknown")?;
+                    let new_user_id = unknown.id;
+                    pending.push(BoardEvent::UserCreated { user: UserJson::from(unknown) });
+                    new_user_id
+                }
+            };
+            report.reassigned_messages += diesel::update(message::table.filter(message::id.eq_any(&orphans)))
+                .set(message::user.eq(unknown))
+                .execute(db_connection)?;
+            reassigned.extend(orphans);
+        }
+        let updated = message::table.filter(message::id.eq_any(&reassigned)).order(message::id).load::<PostMessage>(db_connection)?;
+        pending.extend(updated.into_iter().map(|item| BoardEvent::MessageUpdated { message: MessageJson::from(item) }));
+        events::record_all(db_connection, &pending)?;
+        Ok((report, pending))
+    })?;
+    pending.into_iter().for_each(events::publish);
+    Ok(report)
+}
+
+/// 把WAL中的内容写回数据库并清空WAL文件
+pub fn checkpoint(db_connection: &SqliteConnection) -> QueryResult<CheckpointResult> {
+    sql_query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .get_result::<CheckpointResult>(db_connection)
+}
+
+/// 服务器停止后调用：执行最后一次检查点，把WAL合并回数据库文件。
+/// 有其他连接正在写入时检查点无法完成，只会输出警告
+pub fn final_checkpoint(pool: &Pool) {
+    match pool.get().map_err(|e| e.to_string()).and_then(|db_connection| checkpoint(&db_connection).map_err(|e| e.to_string())) {
+        Ok(result) if result.busy == 0 => {
+            tracing::info!(pages = result.checkpointed, "final WAL checkpoint completed")
+        },
+        Ok(result) => tracing::warn!(
+            pages = result.checkpointed,
+            log = result.log,
+            "final WAL checkpoint could not finish because the database is still in use"
+        ),
+        Err(e) => tracing::error!(error = %e, "final WAL checkpoint failed"),
+    }
+}
+
+/// 重建数据库文件，回收删除留言后留下的空间
+pub fn vacuum(db_connection: &SqliteConnection) -> QueryResult<()> {
+    db_connection.batch_execute("VACUUM;")
+}
+
+/// 检查数据库，按需修复问题、执行VACUUM和WAL checkpoint
+pub fn run(db_connection: &SqliteConnection, apply_fixes: bool, compact: bool) -> QueryResult<HealthReport> {
+    let fixed = if apply_fixes { Some(fix(db_connection)?) } else { None };
+    let mut report = check(db_connection)?; //修复之后再检查一次，报告里是修复后的状态
+    report.fixed = fixed;
+    if compact {
+        vacuum(db_connection)?;
+        report.vacuumed = true;
+        report.checkpoint = Some(checkpoint(db_connection)?);
+    }
+    Ok(report)
+}
+
+/// 如果设置了维护间隔，就定时执行VACUUM和WAL checkpoint
+pub fn spawn_maintenance_job(jobs: &mut Jobs, pool: Pool, options: MaintenanceOptions) {
+    let period = match options.interval {
+        Some(period) => period,
+        None => return,
+    };
+    jobs.spawn("maintenance", rt::time::Instant::now() + period, period, move || {
+        let pool = pool.clone();
+        async move {
+            let result = web::block(move || {
+                let db_connection = pool.get().map_err(|e| e.to_string())?;
+                vacuum(&db_connection).map_err(|e| e.to_string())?;
+                checkpoint(&db_connection).map_err(|e| e.to_string())
+            }).await;
+            if let Err(e) = result {
+                tracing::error!(error = %e, "scheduled maintenance failed");
+            }
+        }
+    });
+}
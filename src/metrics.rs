@@ -0,0 +1,152 @@
+use std::{pin::Pin, task::{Context, Poll}, time::Instant};
+use actix_web::{Error, HttpResponse, Responder, dev::{Service, ServiceRequest, ServiceResponse, Transform}, get, web};
+use diesel::r2d2::{HandleEvent, event::{CheckoutEvent, TimeoutEvent}};
+use futures::future::{Future, Ready, ok};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+};
+use crate::Pool;
+
+// 所有指标都注册在prometheus的默认registry里，`GET /metrics`时统一导出
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("http_requests_total", "HTTP requests by route and status", &["method", "route", "status"])
+        .unwrap()
+});
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!("http_request_duration_seconds", "HTTP request latency by route", &["method", "route"])
+        .unwrap()
+});
+static DB_QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "db_query_duration_seconds",
+        "Database query latency by query",
+        &["query"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]
+    )
+    .unwrap()
+});
+static POOL_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("db_pool_connections", "Open connections in the database pool").unwrap()
+});
+static POOL_IDLE_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("db_pool_idle_connections", "Idle connections in the database pool").unwrap()
+});
+static POOL_MAX_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("db_pool_max_size", "Maximum size of the database pool").unwrap()
+});
+static POOL_WAIT: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "db_pool_wait_seconds",
+        "Time spent waiting to check out a pooled connection",
+        vec![0.0001, 0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 30.0]
+    )
+    .unwrap()
+});
+static POOL_TIMEOUTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("db_pool_timeouts_total", "Connection checkouts that timed out").unwrap()
+});
+pub static MESSAGES_CREATED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("messages_created_total", "Messages posted through the API").unwrap()
+});
+pub static USERS_AUTO_CREATED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("users_auto_created_total", "Users created implicitly by posting a message").unwrap()
+});
+pub static MESSAGE_CLEARS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("message_clears_total", "Times all messages were cleared").unwrap()
+});
+
+/// 执行一次数据库操作并按`query`记录耗时
+pub fn time_query<T>(query: &str, operation: impl FnOnce() -> T) -> T {
+    let _timer = DB_QUERY_DURATION.with_label_values(&[query]).start_timer();
+    operation()
+}
+
+/// 连接池的事件回调，记录取连接的等待时间和超时次数
+#[derive(Debug)]
+pub struct PoolMetrics;
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        POOL_WAIT.observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        POOL_TIMEOUTS.inc();
+    }
+}
+
+#[get("/metrics")]
+pub async fn export_metrics(pool: web::Data<Pool>) -> impl Responder {
+    let state = pool.state();
+    POOL_CONNECTIONS.set(state.connections as i64);
+    POOL_IDLE_CONNECTIONS.set(state.idle_connections as i64);
+    POOL_MAX_SIZE.set(pool.max_size() as i64); //连接池的状态在导出时读取
+    let mut buffer = Vec::new();
+    match TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(buffer),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Unable to encode metrics: {}", e)),
+    }
+}
+
+/// 按路由模板和状态码统计请求数和耗时。用模板而不是实际路径，避免标签数量失控
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let method = request.method().to_string();
+        let route = request.match_pattern().unwrap_or_else(|| String::from("unmatched"));
+        let started = Instant::now();
+        let future = self.service.call(request);
+        Box::pin(async move {
+            let result = future.await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            HTTP_REQUESTS.with_label_values(&[&method, &route, status.as_str()]).inc();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[&method, &route])
+                .observe(started.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
@@ -1,8 +1,20 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use crate::schema::*;
-use chrono;
+use crate::timezone::Zone;
 
-#[derive(Debug, Insertable, Queryable)]
+pub const MAX_NAME_LENGTH: usize = 20;
+pub const MAX_TITLE_LENGTH: usize = 100;
+pub const MAX_CONTENT_LENGTH: usize = 400;
+
+/// 解析RFC 3339格式的时间并转换为UTC；也接受旧版本`NaiveDateTime::to_string()`的格式，视为UTC时间
+pub fn parse_timestamp(text: &str) -> chrono::ParseResult<chrono::NaiveDateTime> {
+    chrono::DateTime::parse_from_rfc3339(text)
+        .map(|time| time.naive_utc())
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"))
+}
+
+#[derive(Debug, Clone, Insertable, Queryable)]
 #[table_name = "message"]
 pub struct PostMessage {
     pub id: i32,
@@ -10,21 +22,28 @@ pub struct PostMessage {
     pub title: String,
     pub content: String,
     pub pub_date: chrono::NaiveDateTime,
-} //用来与数据库进行交互的结构体
+} //用来与数据库进行交互的结构体，时间均为UTC
 
 impl From<PostMessage> for MessageJson {
     fn from(item: PostMessage) -> Self {
+        MessageJson::in_zone(item, &Zone::Utc)
+    }
+}
+
+impl MessageJson {
+    /// 转换时把`pub_date`格式化为指定时区下的RFC 3339时间
+    pub fn in_zone(item: PostMessage, zone: &Zone) -> Self {
         MessageJson {
             id: item.id,
             user: item.user,
             title: item.title,
             content: item.content,
-            pub_date: item.pub_date.to_string(),
+            pub_date: zone.format(&item.pub_date),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct MessageJson {
     pub id: i32,
     pub user: i32,
@@ -33,7 +52,7 @@ pub struct MessageJson {
     pub pub_date: String,
 } //用来转Json的结构体，虽然没有明白和Message分开有什么必要……
 
-#[derive(Debug, Insertable, Queryable)]
+#[derive(Debug, Clone, Insertable, Queryable)]
 #[table_name = "user"]
 pub struct PostUser {
     pub id: i32,
@@ -46,20 +65,44 @@ impl From<PostUser> for UserJson {
         UserJson {
             id: item.id,
             name: item.name,
-            register_date: item.register_date.to_string()
+            register_date: Zone::Utc.format(&item.register_date)
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct UserJson {
     pub id: i32,
     pub name: String,
     pub register_date: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ReceiveMessageJson {
     pub title: String,
     pub content: String,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: String, //逗号分隔的事件名
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook: i32,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: chrono::NaiveDateTime,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+}
@@ -0,0 +1,447 @@
+use actix_web::{HttpResponse, Responder, get};
+use once_cell::sync::Lazy;
+use schemars::{JsonSchema, gen::{SchemaGenerator, SchemaSettings}};
+use serde_json::{Value, json};
+use crate::error::ErrorBody;
+use crate::models::{MessageJson, ReceiveMessageJson, UserJson};
+use crate::retention::PurgeReport;
+use crate::transfer::ImportReport;
+use crate::webhooks::{DeliveryJson, ReceiveWebhookJson, WebhookJson};
+
+/// 文档页面，不依赖外部的脚本和样式
+const DOCS_PAGE: &str = include_str!("docs.html");
+
+static SPEC: Lazy<Value> = Lazy::new(spec);
+
+fn schema_ref<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(generator.subschema_for::<T>()).unwrap_or(Value::Null)
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/ErrorBody" } } },
+    })
+}
+
+/// 打开`api.django_compat`时v1的错误是纯文本
+fn v1_error(description: &str) -> Value {
+    let mut response = error_response(description);
+    response["content"]["text/plain"] = json!({ "schema": { "type": "string" } });
+    response
+}
+
+fn boolean_query(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "required": false, "description": description, "schema": { "type": "boolean" } })
+}
+
+fn feed_operation(operation_id: &str, summary: &str, content_type: &str, per_user: bool) -> Value {
+    let mut parameters = vec![json!({ "name": "page", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 1, "default": 1 } })];
+    let mut responses = json!({
+        "200": { "description": "订阅内容，带有first、previous、next分页链接", "content": { content_type: { "schema": { "type": "string" } } } },
+        "400": error_response("`page`不合法"),
+    });
+    if per_user {
+        parameters.push(json!({ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } }));
+        responses["404"] = error_response("用户不存在");
+    }
+    json!({
+        "get": {
+            "tags": ["feeds"],
+            "operationId": operation_id,
+            "summary": summary,
+            "parameters": parameters,
+            "responses": responses,
+        },
+    })
+}
+
+/// 由模型类型生成结构定义，再加上每个接口的说明，组成OpenAPI 3文档
+pub fn spec() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let message = schema_ref::<MessageJson>(&mut generator);
+    let receive_message = schema_ref::<ReceiveMessageJson>(&mut generator);
+    let user = schema_ref::<UserJson>(&mut generator);
+    schema_ref::<ErrorBody>(&mut generator); //错误响应都引用`#/components/schemas/ErrorBody`
+    let import_report = schema_ref::<ImportReport>(&mut generator);
+    let purge_report = schema_ref::<PurgeReport>(&mut generator);
+    let receive_webhook = schema_ref::<ReceiveWebhookJson>(&mut generator);
+    let webhook = schema_ref::<WebhookJson>(&mut generator);
+    let delivery = schema_ref::<DeliveryJson>(&mut generator);
+    let webhook_id = json!({ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } });
+    let user_cookie = json!({
+        "name": "user", "in": "cookie", "required": false,
+        "description": "发帖的用户名，不存在时自动创建；没有时为`Unknown`",
+        "schema": { "type": "string" },
+    });
+    let list_parameters = json!([
+        { "name": "limit", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 0, "default": 100 } },
+        { "name": "offset", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 0, "default": 0 } },
+        { "name": "tz", "in": "query", "required": false, "description": "展示`pub_date`所用的时区，IANA名称或`+08:00`这样的偏移，默认UTC", "schema": { "type": "string" } },
+        { "name": "format", "in": "query", "required": false, "description": "`csv`或`json`，优先于`Accept`", "schema": { "type": "string", "enum": ["json", "csv"] } },
+        boolean_query("bom", "CSV以UTF-8 BOM开头，方便Excel识别编码"),
+        { "name": "Accept", "in": "header", "required": false, "description": "`text/csv`的权重高于`application/json`时输出CSV", "schema": { "type": "string" } },
+        { "name": "If-None-Match", "in": "header", "required": false, "description": "上次响应的`ETag`，没有变化时返回304", "schema": { "type": "string" } },
+        { "name": "If-Modified-Since", "in": "header", "required": false, "description": "上次响应的`Last-Modified`，没有`If-None-Match`时才使用", "schema": { "type": "string" } },
+    ]);
+    let csv_content = json!({ "schema": { "type": "string", "description": "带表头的CSV：id,user,title,content,pub_date。以=、+、-、@、制表符或回车开头的文本前面会加上'，防止表格软件当作公式执行" } });
+    let not_modified = json!({ "description": "这一页留言没有变化，响应体为空" });
+    let message_body = json!({
+        "required": true,
+        "content": {
+            "application/json": { "schema": receive_message },
+            "application/x-www-form-urlencoded": { "schema": receive_message },
+        },
+    });
+    let too_many_requests = json!({ "description": "超出限流，`Retry-After`给出需要等待的秒数" });
+    let mut spec = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "backend-demo",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "留言板接口。出错时返回`ErrorBody`，`request_id`和响应头`X-Request-Id`相同。",
+        },
+        "tags": [
+            { "name": "messages" },
+            { "name": "graphql", "description": "留言和作者的GraphQL接口，作者按请求批量查询" },
+            { "name": "events", "description": "实时推送，代替轮询留言列表" },
+            { "name": "feeds", "description": "按发表时间从新到旧，每页50条" },
+            { "name": "v1", "description": "已弃用，响应带有`Deprecation`头，请改用`/api/v2`。不带版本号的路径是v1的别名" },
+            { "name": "admin", "description": "管理接口，不允许跨域访问" },
+            { "name": "operations", "description": "健康检查、监控和文档" },
+        ],
+        "paths": {
+            "/api/v2/messages": {
+                "get": {
+                    "tags": ["messages"],
+                    "operationId": "listMessages",
+                    "summary": "按id顺序列出留言",
+                    "parameters": list_parameters,
+                    "responses": {
+                        "200": { "description": "留言列表", "content": { "application/json": { "schema": { "type": "array", "items": message } }, "text/csv": csv_content } },
+                        "304": not_modified,
+                        "400": error_response("`limit`、`offset`、`tz`、`format`或`bom`不合法"),
+                        "429": too_many_requests,
+                        "503": error_response("暂时取不到数据库连接"),
+                    },
+                },
+                "post": {
+                    "tags": ["messages"],
+                    "operationId": "createMessage",
+                    "summary": "发表留言",
+                    "parameters": [user_cookie],
+                    "requestBody": message_body,
+                    "responses": {
+                        "201": { "description": "保存后的留言", "content": { "application/json": { "schema": message } } },
+                        "400": error_response("请求体无法解析，或者字段超出长度限制"),
+                        "413": error_response("请求体超过`limits.message_payload_bytes`"),
+                        "415": error_response("不支持的`Content-Type`"),
+                        "429": too_many_requests,
+                        "503": error_response("暂时取不到数据库连接"),
+                    },
+                },
+                "delete": {
+                    "tags": ["admin"],
+                    "operationId": "deleteMessages",
+                    "summary": "删除所有留言",
+                    "responses": {
+                        "200": { "description": "删除的条数", "content": { "application/json": { "schema": { "type": "object", "properties": { "deleted": { "type": "integer" } } } } } },
+                        "500": error_response("数据库出错"),
+                    },
+                },
+            },
+            "/api/admin/backup": {
+                "post": {
+                    "tags": ["admin"],
+                    "operationId": "createBackup",
+                    "summary": "立即创建一个数据库快照",
+                    "responses": {
+                        "201": { "description": "快照文件的路径", "content": { "application/json": { "schema": { "type": "object", "properties": { "path": { "type": "string" } } } } } },
+                        "500": { "description": "备份失败" },
+                    },
+                },
+            },
+            "/api/admin/export": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "exportBoard",
+                    "summary": "以JSONL导出所有用户和留言，每行是一个`UserJson`或`MessageJson`",
+                    "responses": {
+                        "200": { "description": "先输出所有用户，再输出所有留言", "content": { "application/x-ndjson": { "schema": { "oneOf": [user, message] } } } },
+                    },
+                },
+            },
+            "/api/admin/import": {
+                "post": {
+                    "tags": ["admin"],
+                    "operationId": "importBoard",
+                    "summary": "导入`/api/admin/export`格式的JSONL",
+                    "parameters": [boolean_query("dry_run", "只检查不写入")],
+                    "requestBody": { "required": true, "content": { "application/x-ndjson": { "schema": { "oneOf": [user, message] } } } },
+                    "responses": {
+                        "200": { "description": "导入结果，出错的行列在`errors`中", "content": { "application/json": { "schema": import_report } } },
+                        "500": { "description": "导入失败" },
+                    },
+                },
+            },
+            "/api/admin/retention": {
+                "post": {
+                    "tags": ["admin"],
+                    "operationId": "runRetention",
+                    "summary": "立即按保留策略清理一次",
+                    "responses": {
+                        "200": { "description": "删除的留言数", "content": { "application/json": { "schema": purge_report } } },
+                        "409": { "description": "没有配置保留策略" },
+                        "500": { "description": "清理失败" },
+                    },
+                },
+            },
+            "/api/admin/maintenance": {
+                "post": {
+                    "tags": ["admin"],
+                    "operationId": "runMaintenance",
+                    "summary": "检查数据库的完整性",
+                    "parameters": [
+                        boolean_query("fix", "修复作者不存在的留言和同名用户"),
+                        boolean_query("vacuum", "再执行`VACUUM`和`wal_checkpoint(TRUNCATE)`"),
+                    ],
+                    "responses": {
+                        "200": { "description": "检查结果", "content": { "application/json": { "schema": { "type": "object" } } } },
+                        "500": { "description": "检查失败" },
+                    },
+                },
+            },
+            "/api/admin/webhooks": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "listWebhooks",
+                    "summary": "列出所有webhook，不包括`secret`",
+                    "responses": {
+                        "200": { "description": "按id排列", "content": { "application/json": { "schema": { "type": "array", "items": webhook } } } },
+                    },
+                },
+                "post": {
+                    "tags": ["admin"],
+                    "operationId": "createWebhook",
+                    "summary": "登记一个webhook，订阅的事件发生后以POST投递，失败时按指数退避重试",
+                    "requestBody": { "required": true, "content": { "application/json": { "schema": receive_webhook } } },
+                    "responses": {
+                        "201": { "description": "登记的webhook，带有签名用的`secret`", "content": { "application/json": { "schema": webhook } } },
+                        "400": error_response("`url`不是http或https地址，或者`events`为空、包含未知的事件"),
+                    },
+                },
+            },
+            "/api/admin/webhooks/{id}": {
+                "delete": {
+                    "tags": ["admin"],
+                    "operationId": "deleteWebhook",
+                    "summary": "删除webhook和它的投递记录",
+                    "parameters": [webhook_id],
+                    "responses": {
+                        "204": { "description": "已删除" },
+                        "404": error_response("webhook不存在"),
+                    },
+                },
+            },
+            "/api/admin/webhooks/{id}/deliveries": {
+                "get": {
+                    "tags": ["admin"],
+                    "operationId": "listWebhookDeliveries",
+                    "summary": "投递记录，从新到旧",
+                    "parameters": [
+                        webhook_id,
+                        { "name": "status", "in": "query", "required": false, "schema": { "type": "string", "enum": ["pending", "delivered", "failed"] } },
+                        { "name": "limit", "in": "query", "required": false, "schema": { "type": "integer", "minimum": 1, "maximum": 500, "default": 50 } },
+                    ],
+                    "responses": {
+                        "200": { "description": "投递记录", "content": { "application/json": { "schema": { "type": "array", "items": delivery } } } },
+                        "400": error_response("`status`或`limit`不合法"),
+                        "404": error_response("webhook不存在"),
+                    },
+                },
+            },
+            "/feed.atom": feed_operation("boardAtom", "全站留言的Atom订阅", "application/atom+xml", false),
+            "/feed.rss": feed_operation("boardRss", "全站留言的RSS订阅", "application/rss+xml", false),
+            "/user/{name}/feed.atom": feed_operation("userAtom", "某个用户的留言的Atom订阅", "application/atom+xml", true),
+            "/user/{name}/feed.rss": feed_operation("userRss", "某个用户的留言的RSS订阅", "application/rss+xml", true),
+            "/healthz": {
+                "get": {
+                    "tags": ["operations"],
+                    "operationId": "liveness",
+                    "summary": "存活检查",
+                    "responses": { "200": { "description": "进程正常", "content": { "application/json": { "schema": { "type": "object" } } } } },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "tags": ["operations"],
+                    "operationId": "readiness",
+                    "summary": "就绪检查：连接池、查询、迁移版本和WAL",
+                    "responses": {
+                        "200": { "description": "可以处理请求", "content": { "application/json": { "schema": { "type": "object" } } } },
+                        "503": { "description": "任何一项检查失败，`checks`中有详细结果", "content": { "application/json": { "schema": { "type": "object" } } } },
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "tags": ["operations"],
+                    "operationId": "metrics",
+                    "summary": "Prometheus格式的监控指标",
+                    "responses": { "200": { "description": "文本格式的指标", "content": { "text/plain": { "schema": { "type": "string" } } } } },
+                },
+            },
+            "/api/graphql": {
+                "post": {
+                    "tags": ["graphql"],
+                    "operationId": "graphql",
+                    "summary": "执行GraphQL查询或变更",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["query"],
+                            "properties": {
+                                "query": { "type": "string" },
+                                "operationName": { "type": "string" },
+                                "variables": { "type": "object" },
+                            },
+                        } } },
+                    },
+                    "responses": {
+                        "200": { "description": "GraphQL响应，字段出错时错误在`errors`中，`extensions.code`和`ErrorBody.code`相同", "content": { "application/json": { "schema": { "type": "object" } } } },
+                        "400": error_response("请求体不是合法的GraphQL请求"),
+                        "413": error_response("请求体超过`limits.message_payload_bytes`"),
+                        "429": too_many_requests,
+                    },
+                },
+                "get": {
+                    "tags": ["graphql"],
+                    "operationId": "graphiql",
+                    "summary": "GraphiQL调试页面",
+                    "responses": {
+                        "200": { "description": "HTML页面", "content": { "text/html": { "schema": { "type": "string" } } } },
+                        "404": error_response("`api.graphiql`没有打开"),
+                    },
+                },
+            },
+            "/api/message/stream": {
+                "get": {
+                    "tags": ["events"],
+                    "operationId": "eventStream",
+                    "summary": "Server-Sent Events：推送留言的新增、删除和清空",
+                    "description": "`message-created`和`message-deleted`的`id`是留言id，`data`是`MessageJson`；`message-updated`没有`id`，`data`是`MessageJson`；\
+                        `board-cleared`没有`id`，`data`为`{\"deleted\": n}`。\
+                        每隔`api.heartbeat_secs`秒发送一行注释保持连接。",
+                    "parameters": [
+                        { "name": "Last-Event-ID", "in": "header", "required": false, "description": "重连时由浏览器自动带上，先补发id更大的留言再转为实时推送", "schema": { "type": "integer" } },
+                    ],
+                    "responses": {
+                        "200": { "description": "事件流", "content": { "text/event-stream": { "schema": { "type": "string" } } } },
+                        "400": error_response("`Last-Event-ID`不是留言id"),
+                    },
+                },
+            },
+            "/api/ws": {
+                "get": {
+                    "tags": ["events"],
+                    "operationId": "events",
+                    "summary": "WebSocket：推送留言的新增、删除和清空",
+                    "description": "每条消息是一个JSON对象，`type`为`message.created`、`message.deleted`、`message.updated`（都带有`message`）、`board.cleared`（带有`deleted`）或`user.created`（带有`user`）。\
+                        服务器每隔`api.heartbeat_secs`秒发送Ping，超过`api.client_timeout_secs`秒没有收到客户端的数据时断开；\
+                        积压的事件超过`api.event_buffer`条时以1008关闭连接。",
+                    "parameters": [
+                        { "name": "user", "in": "query", "required": false, "description": "只接收这些用户的事件，可以出现多次", "schema": { "type": "array", "items": { "type": "string" } }, "style": "form", "explode": true },
+                    ],
+                    "responses": {
+                        "101": { "description": "切换到WebSocket协议" },
+                        "400": { "description": "不是WebSocket握手请求" },
+                        "404": error_response("`user`指定的用户不存在"),
+                    },
+                },
+            },
+            "/api/openapi.json": {
+                "get": {
+                    "tags": ["operations"],
+                    "operationId": "openapi",
+                    "summary": "本文档",
+                    "responses": { "200": { "description": "OpenAPI 3文档", "content": { "application/json": { "schema": { "type": "object" } } } } },
+                },
+            },
+            "/api/docs": {
+                "get": {
+                    "tags": ["operations"],
+                    "operationId": "docs",
+                    "summary": "浏览本文档并直接调用接口的页面",
+                    "responses": { "200": { "description": "HTML页面", "content": { "text/html": { "schema": { "type": "string" } } } } },
+                },
+            },
+        },
+        "components": {
+            "schemas": generator.take_definitions(),
+        },
+    });
+    for (prefix, suffix) in [("/api", "Alias"), ("/api/v1", "")] {
+        spec["paths"][format!("{}/message", prefix)] = json!({
+            "get": {
+                "tags": ["v1"],
+                "operationId": format!("listMessagesV1{}", suffix),
+                "summary": "按id顺序列出留言",
+                "deprecated": true,
+                "parameters": list_parameters,
+                "responses": {
+                    "200": {
+                        "description": "每一项都是一个JSON编码后的`MessageJson`字符串",
+                        "content": {
+                            "application/json": { "schema": { "type": "array", "items": { "type": "string", "x-decoded-schema": message } } },
+                            "text/csv": csv_content,
+                        },
+                    },
+                    "304": not_modified,
+                    "400": v1_error("`limit`、`offset`、`tz`、`format`或`bom`不合法"),
+                    "429": too_many_requests,
+                    "503": v1_error("暂时取不到数据库连接"),
+                },
+            },
+            "post": {
+                "tags": ["v1"],
+                "operationId": format!("postMessageV1{}", suffix),
+                "summary": "发表留言",
+                "deprecated": true,
+                "parameters": [user_cookie],
+                "requestBody": message_body,
+                "responses": {
+                    "201": { "description": "留言已保存", "content": { "text/plain": { "schema": { "type": "string" } } } },
+                    "400": v1_error("请求体无法解析，或者字段超出长度限制"),
+                    "413": v1_error("请求体超过`limits.message_payload_bytes`"),
+                    "415": v1_error("不支持的`Content-Type`"),
+                    "429": too_many_requests,
+                    "503": v1_error("暂时取不到数据库连接"),
+                },
+            },
+        });
+        spec["paths"][format!("{}/clearmessage", prefix)] = json!({
+            "get": {
+                "tags": ["v1"],
+                "operationId": format!("clearMessagesV1{}", suffix),
+                "summary": "删除所有留言",
+                "deprecated": true,
+                "responses": {
+                    "200": { "description": "已删除", "content": { "text/plain": { "schema": { "type": "string" } } } },
+                    "500": v1_error("数据库出错"),
+                },
+            },
+        });
+    } //v1和它的别名完全相同
+    spec
+}
+
+#[get("/api/openapi.json")]
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(&*SPEC)
+}
+
+#[get("/api/docs")]
+pub async fn docs() -> impl Responder {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(DOCS_PAGE)
+}
@@ -1,124 +1,404 @@
-use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, get, web::{self, Bytes}};
+use std::time::{Duration, SystemTime};
+use actix_web::{
+    Error, HttpMessage, HttpRequest, HttpResponse,
+    http::{StatusCode, header::{self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch}},
+    web::{self, Bytes},
+};
 use qstring::QString;
-use diesel::{RunQueryDsl, insert_into, prelude::*};
+use diesel::{RunQueryDsl, prelude::*, sql_query, sql_types::{BigInt, Integer, Nullable, Timestamp}};
 use serde_json::json;
-use chrono::prelude::*;
 use crate::Pool;
+use crate::board;
+use crate::metrics;
+use crate::config::ContentLimits;
+use crate::csv::{self, ListFormat};
+use crate::error::ApiError;
+use crate::events::{self, BoardEvent};
 use crate::models::*;
+use crate::timezone::Zone;
 
-#[get("/api/message")]
-pub async fn get_message(request: HttpRequest, pool: web::Data<Pool>) -> impl Responder {
+/// `GET`留言列表的查询参数，v1和v2共用
+#[derive(Debug)]
+pub struct MessageQuery {
+    pub limit: u32,
+    pub offset: u32,
+    pub zone: Zone,
+}
+
+impl MessageQuery {
+    pub fn parse(query_string: &str) -> Result<Self, ApiError> {
+        let query_string  = QString::from(query_string);
+
+        let limit = query_string.get("limit");
+        let limit: u32 = match limit {
+            Some(limit) => 
+                match limit.parse::<u32>() {
+                    Ok(val) => val, //有这个字段而且是合法正整数，获取这个值
+                    Err(_) => return Err(ApiError::invalid_field("limit", format!("{} is not a number", limit))),
+                }, //有这个字段但不是合法正整数，返回400
+            None => 100 //没有这个字段，默认为100
+        };
+        let offset = query_string.get("offset");
+        let offset: u32 = match offset {
+            Some(offset) =>
+                match offset.parse::<u32>() {
+                    Ok(val) => val,
+                    Err(_) => return Err(ApiError::invalid_field("offset", format!("{} is not a number", offset))),
+                },
+            None => 0
+        };
+        let zone = match query_string.get("tz") {
+            Some(tz) => Zone::parse(tz).map_err(|e| ApiError::invalid_field("tz", e))?, //客户端可以指定展示时间所用的时区
+            None => Zone::Utc
+        };
+        Ok(MessageQuery { limit, offset, zone })
+    }
+}
+
+/// 按id顺序取出一页留言，`pub_date`转换为请求的时区
+pub fn load_messages(pool: &Pool, query: &MessageQuery) -> Result<Vec<MessageJson>, ApiError> {
     use crate::schema::message::dsl::*;
-    let db_connection = pool.get().unwrap();
-    let query_string = request.query_string();
-    let query_string  = QString::from(query_string);
-
-    let limit = query_string.get("limit");
-    let limit: u32 = match limit {
-        Some(limit) => 
-            match limit.parse::<u32>() {
-                Ok(val) => val, //有这个字段而且是合法正整数，获取这个值
-                Err(_) => return HttpResponse::BadRequest().body(format!("{} is not a number", limit)),
-            }, //有这个字段但不是合法正整数，返回400
-        None => 100 //没有这个字段，默认为100
+    let db_connection = pool.get()?;
+    let messages = metrics::time_query("list_messages", || message
+        .order(id)
+        .limit(query.limit as i64)
+        .offset(query.offset as i64)
+        .load::<PostMessage>(&db_connection))?;
+    Ok(messages.into_iter().map(|x| MessageJson::in_zone(x, &query.zone)).collect())
+}
+
+/// 一页留言的版本：窗口内的行数和最大id，以及留言表最后一次变化的序号和时间。
+/// `board_revision`由触发器维护，任何增删改都会让ETag变化
+#[derive(Debug, QueryableByName)]
+pub struct ListingVersion {
+    #[sql_type = "BigInt"]
+    pub count: i64,
+    #[sql_type = "Nullable<Integer>"]
+    pub max_id: Option<i32>,
+    #[sql_type = "BigInt"]
+    pub revision: i64,
+    #[sql_type = "Timestamp"]
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl ListingVersion {
+    pub fn load(pool: &Pool, query: &MessageQuery) -> Result<Self, ApiError> {
+        let db_connection = pool.get()?;
+        let version = metrics::time_query("listing_version", || sql_query(
+            "SELECT COUNT(page.id) AS count, MAX(page.id) AS max_id, \
+                board_revision.revision AS revision, board_revision.updated_at AS updated_at \
+             FROM board_revision LEFT JOIN (SELECT id FROM message ORDER BY id LIMIT ? OFFSET ?) AS page"
+        )
+            .bind::<BigInt, _>(query.limit as i64)
+            .bind::<BigInt, _>(query.offset as i64)
+            .get_result::<ListingVersion>(&db_connection))?;
+        Ok(version)
+    }
+
+    /// `representation`区分同一页留言的不同格式，接口版本或时区不同时ETag也不同
+    pub fn etag(&self, representation: &str, zone: &Zone) -> EntityTag {
+        let variant = fnv1a(format!("{}|{}", representation, zone.name()).as_bytes());
+        EntityTag::strong(format!("{}-{}-{}-{:x}", self.revision, self.count, self.max_id.unwrap_or(0), variant))
+    }
+
+    pub fn last_modified(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.updated_at.timestamp_millis().max(0) as u64)
+    }
+}
+
+/// 64位FNV-1a。和`DefaultHasher`不同，结果不随编译器版本变化，升级后客户端缓存的ETag仍然有效
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// 客户端缓存的内容是否仍然有效。有`If-None-Match`时只看它，否则看`If-Modified-Since`
+fn not_modified(request: &HttpRequest, etag: &EntityTag, last_modified: SystemTime) -> bool {
+    match request.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => match request.get_header::<IfModifiedSince>() {
+            Some(IfModifiedSince(since)) => last_modified < SystemTime::from(since), //`Last-Modified`只精确到秒，和`since`同一秒的修改都当作有变化
+            None => false,
+        },
+    }
+}
+
+/// 列出留言并处理条件请求：先查版本，客户端的缓存仍然有效时直接返回304，不再查询和序列化留言。
+/// 请求CSV时以流的形式输出，JSON由`render`生成
+pub fn conditional_listing(
+    request: &HttpRequest,
+    pool: &Pool,
+    representation: &str,
+    render: impl FnOnce(Vec<MessageJson>) -> HttpResponse,
+) -> Result<HttpResponse, ApiError> {
+    let query = MessageQuery::parse(request.query_string())?;
+    let format = ListFormat::negotiate(request)?;
+    let version = ListingVersion::load(pool, &query)?; //先取版本再取留言，两次查询之间有写入时下一次轮询会拿到新的内容
+    let etag = version.etag(&format!("{}-{}", representation, format.key()), &query.zone);
+    let last_modified = version.last_modified();
+    let mut response = if not_modified(request, &etag, last_modified) {
+        HttpResponse::build(StatusCode::NOT_MODIFIED).finish()
+    } else {
+        match format {
+            ListFormat::Json => render(load_messages(pool, &query)?),
+            ListFormat::Csv { bom } => stream_messages_csv(pool.clone(), query, bom),
+        }
     };
-    let offset = query_string.get("offset");
-    let offset: u32 = match offset {
-        Some(offset) =>
-            match offset.parse::<u32>() {
-                Ok(val) => val,
-                Err(_) => return HttpResponse::BadRequest().body(format!("{} is not a number", offset)),
-            },
-        None => 0
+    let headers = response.headers_mut();
+    for (name, value) in [
+        (header::ETAG, etag.to_string()),
+        (header::LAST_MODIFIED, HttpDate::from(last_modified).to_string()),
+        (header::CACHE_CONTROL, String::from("no-cache")),
+        (header::VARY, String::from("Accept")),
+    ] {
+        if let Ok(value) = header::HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    Ok(response)
+}
+
+/// 导出CSV时每次从数据库读取的条数
+const CSV_PAGE_SIZE: u32 = 500;
+
+enum CsvCursor {
+    Header,
+    Rows { after: Option<i32>, remaining: u32 },
+    Done,
+}
+
+/// 以CSV输出一页留言。先输出表头，再按id分批读取，不把整个结果放进内存
+fn stream_messages_csv(pool: Pool, query: MessageQuery, bom: bool) -> HttpResponse {
+    let MessageQuery { limit, offset, zone } = query;
+    let stream = futures::stream::unfold(CsvCursor::Header, move |cursor| {
+        let pool = pool.clone();
+        async move {
+            let (after, remaining) = match cursor {
+                CsvCursor::Header => {
+                    let next = CsvCursor::Rows { after: None, remaining: limit };
+                    return Some((Ok(Bytes::from(csv::header::<MessageJson>(bom))), next));
+                },
+                CsvCursor::Rows { remaining: 0, .. } | CsvCursor::Done => return None,
+                CsvCursor::Rows { after, remaining } => (after, remaining),
+            };
+            let batch = remaining.min(CSV_PAGE_SIZE);
+            let page = web::block(move || -> Result<Vec<PostMessage>, ApiError> {
+                use crate::schema::message::dsl::*;
+                let db_connection = pool.get()?;
+                let page = match after {
+                    Some(after) => message.filter(id.gt(after)).order(id).limit(batch as i64).load::<PostMessage>(&db_connection)?,
+                    None => message.order(id).limit(batch as i64).offset(offset as i64).load::<PostMessage>(&db_connection)?,
+                }; //第一批按offset跳过，之后从上一批的最后一个id继续
+                Ok(page)
+            }).await;
+            match page {
+                Ok(page) if page.is_empty() => None,
+                Ok(page) => {
+                    let next = match page.last() {
+                        Some(last) if (page.len() as u32) == batch => CsvCursor::Rows { after: Some(last.id), remaining: remaining - batch },
+                        _ => CsvCursor::Done,
+                    };
+                    let records: Vec<MessageJson> = page.into_iter().map(|x| MessageJson::in_zone(x, &zone)).collect();
+                    Some((Ok(Bytes::from(csv::rows(&records))), next))
+                },
+                Err(e) => {
+                    tracing::error!(error = %e, "CSV export failed");
+                    Some((Err(e), CsvCursor::Done))
+                },
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type(csv::CONTENT_TYPE)
+        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"messages.csv\"")
+        .streaming(Box::pin(stream))
+}
+
+/// v1：每一项都是再编码一次的JSON字符串，和原来的Django版本保持一致
+pub async fn get_message(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    conditional_listing(&request, &pool, "v1", |messages| {
+        let return_objects: Vec<String> = messages
+            .into_iter()
+            .map(|x| json!(x).to_string())
+            .collect(); //获取所有message，按照offset和limit进行筛选，然后将所有得到的PostMessage类型对象转换为MessageJson对象，然后Serialize
+        HttpResponse::Ok().json(return_objects)
+    })
+}
+
+/// 按`Content-Type`解析留言，支持JSON和表单。
+/// 不支持的类型返回415，不是UTF-8或格式错误时返回400并指出出错的位置
+fn decode_message(request: &HttpRequest, body: &[u8]) -> Result<ReceiveMessageJson, ApiError> {
+    let mime = match request.mime_type() {
+        Ok(Some(mime)) => mime,
+        Ok(None) => return Err(ApiError::UnsupportedMediaType(
+            String::from("Content-Type must be application/json or application/x-www-form-urlencoded"))),
+        Err(_) => return Err(ApiError::InvalidBody(String::from("Malformed Content-Type header"))),
     };
-    let return_objects: Vec<String> = message
-        .order(id)
-        .limit(limit as i64)
-        .offset(offset as i64)
-        .load::<PostMessage>(&db_connection)
-        .unwrap()
-        .into_iter()
-        .map(|x| MessageJson::from(x))
-        .map(|x| json!(x).to_string())
-        .collect(); //获取所有message，按照offset和limit进行筛选，然后将所有得到的PostMessage类型对象转换为MessageJson对象，然后Serialize
-    HttpResponse::Ok().json(return_objects)
-}
-
-pub async fn get_post_message(request_raw: Bytes, request: HttpRequest, pool: web::Data<Pool>) -> impl Responder {
-    use crate::schema::user::dsl::*;
-    let db_connection = pool.get().unwrap();
-    let username = match request.cookie("user") {
+    let is_json = mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON);
+    let is_form = mime.type_() == mime::APPLICATION && mime.subtype() == mime::WWW_FORM_URLENCODED;
+    if !is_json && !is_form {
+        return Err(ApiError::UnsupportedMediaType(
+            format!("Unsupported Content-Type '{}', use application/json or application/x-www-form-urlencoded", mime)));
+    }
+    let text = std::str::from_utf8(body).map_err(|e| {
+        ApiError::InvalidBody(format!("Request body is not valid UTF-8 (invalid byte at offset {})", e.valid_up_to()))
+    })?;
+    if is_json {
+        serde_json::from_str::<ReceiveMessageJson>(text).map_err(|e| {
+            ApiError::InvalidBody(format!("Invalid JSON at line {} column {}: {}", e.line(), e.column(), e))
+        })
+    } else {
+        serde_urlencoded::from_str::<ReceiveMessageJson>(text)
+            .map_err(|e| ApiError::InvalidBody(format!("Invalid form data: {}", e)))
+    }
+}
+
+/// 解析并检查请求体，找到或创建`user`cookie对应的用户，然后保存留言
+pub fn save_message(
+    request: &HttpRequest,
+    request_raw: Result<Bytes, Error>,
+    pool: &Pool,
+    limits: &ContentLimits,
+) -> Result<PostMessage, ApiError> {
+    let request_raw = request_raw.map_err(ApiError::from_payload)?;
+    let post_data = decode_message(request, &request_raw)?; //先检查请求体，有问题时不会创建用户
+    post_message(pool, limits, &cookie_user(request), &post_data)
+}
+
+/// 发帖人的用户名，没有`user`cookie时为`Unknown`
+pub fn cookie_user(request: &HttpRequest) -> String {
+    match request.cookie("user") {
         Some(cookie) => String::from(cookie.value()),
         None => String::from("Unknown")
-    };
-    if username.len() > 20 {
-        return HttpResponse::BadRequest().body("User name too long");
+    }
+}
+
+/// 检查长度，找到或创建用户后保存留言。REST和GraphQL共用
+pub fn post_message(pool: &Pool, limits: &ContentLimits, username: &str, post_data: &ReceiveMessageJson) -> Result<PostMessage, ApiError> {
+    if post_data.title.len() > limits.max_title_length {
+        return Err(ApiError::invalid_field("title", "Field 'title' Too Long"));
+    } else if post_data.content.len() > limits.max_content_length {
+        return Err(ApiError::invalid_field("content", "Field 'content' Too Long"));
+    }
+    if username.len() > limits.max_name_length {
+        return Err(ApiError::invalid_field("user", "User name too long"));
     } //验证用户名长度合法
-    let message_user = match user.filter(name.eq(&username)).first::<PostUser>(&db_connection) {
-        Ok(vec) => vec,
-        Err(_) => { //先尝试插入一个
-            let new_user_id = match user
-                .order_by(id.desc())
-                .first::<PostUser>(&db_connection) {
-                    Ok(item) => item.id + 1,
-                    Err(_) => 1,
-                };
-            let new_user = PostUser {
-                id: new_user_id,
-                name: username.clone(),
-                register_date: Local::now().naive_local(),
-            };
-            if let Err(_e) = insert_into(user)
-                .values(&new_user)
-                .execute(&db_connection) {
-                    return HttpResponse::BadRequest().body("Validation Error of user:");
-                }
-            new_user
-        }
-    }; //验证用户的存在性，如果存在则得到用户，否则尝试创建
-    if let Ok(text) = String::from_utf8(request_raw.to_vec()) {
-        match serde_json::from_str::<ReceiveMessageJson>(&text) {
-            Ok(post_data) => {
-                if post_data.title.len() > 100 {
-                    return HttpResponse::BadRequest().body("Field 'title' Too Long");
-                } else if post_data.content.len() > 400 {
-                    return HttpResponse::BadRequest().body("Field 'content' Too Long");
-                } else {
-                    use crate::schema::message::dsl::*;
-                    let new_object_id: i32 = match message
-                        .order_by(id.desc())
-                        .first::<PostMessage>(&db_connection) {
-                            Ok(item) => item.id + 1,
-                            Err(_) => 1
-                        }; //从数据库中获取最新的id，加1作为新的id
-                    let new_object = PostMessage {
-                        id: new_object_id,
-                        user: message_user.id,
-                        title: post_data.title,
-                        content: post_data.content,
-                        pub_date: Local::now().naive_local(),
-                    };
-                    if let Err(_e) = insert_into(message)
-                        .values(new_object)
-                        .execute(&db_connection) {
-                            return HttpResponse::InternalServerError().body("Error Saving object");
-                        }
-                    //向数据库中添加内容
-                    return HttpResponse::Created().body("message was sent successfully");
-                }
+    let db_connection = pool.get()?;
+    let mut pending = Vec::new();
+    let (message_user, saved) = db_connection.immediate_transaction(|| -> Result<_, ApiError> {
+        let message_user = match metrics::time_query("find_user", || board::find_user(&db_connection, username))? {
+            Some(item) => item,
+            None => {
+                let item = metrics::time_query("create_user", || board::insert_user(&db_connection, username))?;
+                pending.push(BoardEvent::UserCreated { user: UserJson::from(item.clone()) });
+                item
             },
-            Err(_) => return HttpResponse::BadRequest().body("Json Parse Error")
+        }; //验证用户的存在性，如果存在则得到用户，否则尝试创建
+        let saved = metrics::time_query("create_message", || {
+            board::insert_message(&db_connection, message_user.id, &post_data.title, &post_data.content)
+        })?;
+        pending.push(BoardEvent::MessageCreated { message: MessageJson::from(saved.clone()) });
+        for event in &pending {
+            events::record(&db_connection, event)?;
         }
+        Ok((message_user, saved))
+    })?; //用户、留言和webhook投递在同一个事务里写入
+    if pending.len() > 1 {
+        metrics::USERS_AUTO_CREATED.inc();
+        tracing::info!(user = %message_user.name, user_id = message_user.id, "user created");
     }
-    HttpResponse::InternalServerError().body("Unknown Error")
+    tracing::Span::current().record("user", &message_user.name.as_str());
+    metrics::MESSAGES_CREATED.inc();
+    pending.into_iter().for_each(events::publish);
+    Ok(saved)
 }
 
-#[get("/api/clearmessage")]
-pub async fn clear_message(pool: web::Data<Pool>) -> impl Responder {
-    use crate::schema::message::dsl::*;
-    let db_connection = pool.get().unwrap();
-    match diesel::delete(message).execute(&db_connection) {
-        Ok(_) => HttpResponse::Ok().body("Successfully cleared messages."),
-        Err(_) => HttpResponse::InternalServerError().body("Error while deleting the table"),
+/// v1：成功时返回文本
+pub async fn get_post_message(
+    request_raw: Result<Bytes, Error>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    limits: web::Data<ContentLimits>,
+) -> Result<HttpResponse, ApiError> {
+    save_message(&request, request_raw, &pool, &limits)?;
+    //向数据库中添加内容
+    Ok(HttpResponse::Created().body("message was sent successfully"))
+}
+
+/// 删除所有留言，返回删除的条数
+pub fn delete_messages(pool: &Pool) -> Result<usize, ApiError> {
+    let db_connection = pool.get()?;
+    let deleted = db_connection.immediate_transaction(|| -> Result<_, ApiError> {
+        let deleted = metrics::time_query("clear_messages", || board::clear_messages(&db_connection))?;
+        events::record(&db_connection, &BoardEvent::BoardCleared { deleted })?;
+        Ok(deleted)
+    })?;
+    metrics::MESSAGE_CLEARS.inc();
+    tracing::info!(deleted, "messages cleared");
+    events::publish(BoardEvent::BoardCleared { deleted });
+    Ok(deleted)
+}
+
+/// 删除一条留言，留言不存在时返回`false`
+pub fn delete_message(pool: &Pool, message_id: i32) -> Result<bool, ApiError> {
+    use crate::schema::message::dsl::message;
+    let db_connection = pool.get()?;
+    let deleted = db_connection.immediate_transaction(|| -> Result<_, ApiError> {
+        let found = metrics::time_query("find_message", || message.find(message_id).first::<PostMessage>(&db_connection).optional())?;
+        let found = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        }; //先取出留言，事件里要带上它的内容
+        metrics::time_query("delete_message", || board::delete_message(&db_connection, message_id))?;
+        let event = BoardEvent::MessageDeleted { message: MessageJson::from(found) };
+        events::record(&db_connection, &event)?;
+        Ok(Some(event))
+    })?;
+    match deleted {
+        Some(event) => {
+            tracing::info!(message_id, "message deleted");
+            events::publish(event);
+            Ok(true)
+        },
+        None => Ok(false),
     }
-}
\ No newline at end of file
+}
+
+/// 创建用户，和发帖时自动创建一样产生`user.created`事件
+pub fn create_user(pool: &Pool, user_name: &str) -> Result<PostUser, ApiError> {
+    let db_connection = pool.get()?;
+    let event = db_connection.immediate_transaction(|| -> Result<_, ApiError> {
+        let created = board::insert_user(&db_connection, user_name)?;
+        let event = BoardEvent::UserCreated { user: UserJson::from(created.clone()) };
+        events::record(&db_connection, &event)?;
+        Ok((created, event))
+    })?;
+    let (created, event) = event;
+    tracing::info!(user = %created.name, user_id = created.id, "user created");
+    events::publish(event);
+    Ok(created)
+}
+
+/// 删除用户，一起删除的每条留言都产生`message.deleted`事件。返回值和`board::delete_user`相同
+pub fn delete_user(pool: &Pool, target: &PostUser, with_messages: bool) -> Result<Option<usize>, ApiError> {
+    use crate::schema::message;
+    let db_connection = pool.get()?;
+    let (removed, pending) = db_connection.immediate_transaction(|| -> Result<_, ApiError> {
+        let owned = message::table.filter(message::user.eq(target.id)).order(message::id).load::<PostMessage>(&db_connection)?;
+        let removed = board::delete_user(&db_connection, target, with_messages)?;
+        let pending: Vec<BoardEvent> = match removed {
+            Some(_) => owned.into_iter().map(|item| BoardEvent::MessageDeleted { message: MessageJson::from(item) }).collect(),
+            None => Vec::new(),
+        };
+        events::record_all(&db_connection, &pending)?;
+        Ok((removed, pending))
+    })?;
+    pending.into_iter().for_each(events::publish);
+    Ok(removed)
+}
+
+/// v1：用GET删除留言
+pub async fn clear_message(pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    delete_messages(&pool)?;
+    Ok(HttpResponse::Ok().body("Successfully cleared messages."))
+}
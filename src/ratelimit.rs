@@ -0,0 +1,210 @@
+use std::{collections::HashMap, net::SocketAddr, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, time::{Duration, Instant}};
+use actix_web::{
+    Error, HttpRequest, ResponseError, dev::{ConnectionInfo, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, header},
+};
+use futures::future::{Either, Future, Ready, ok};
+use crate::config::{Quota, RateLimitConfig};
+use crate::error::ApiError;
+
+/// 桶的数量超过这个值时清理已经回满的桶
+const PRUNE_THRESHOLD: usize = 10_000;
+/// 两次清理之间至少间隔这么久，活跃的桶很多时不会每个请求都遍历一遍
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 一次检查的结果，用来生成`X-RateLimit-*`和`Retry-After`头
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_secs: u64,       //桶回满还需要的秒数
+    pub retry_after_secs: u64, //被限制时，下一个令牌可用还需要的秒数
+}
+
+#[derive(Debug)]
+struct Buckets {
+    entries: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+/// 令牌桶：每个键最多积攒`burst`个令牌，每分钟补充`per_minute`个，每个请求消耗一个
+#[derive(Debug)]
+pub struct RateLimiter {
+    quota: Quota,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        RateLimiter { quota, buckets: Mutex::new(Buckets { entries: HashMap::new(), pruned: Instant::now() }) }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.quota.per_minute) / 60.0
+    }
+
+    /// 还有令牌时放行并扣掉一个，用完时拒绝
+    pub fn check(&self, key: &str, now: Instant) -> Decision {
+        let capacity = f64::from(self.quota.burst);
+        let rate = self.refill_per_sec();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.entries.len() > PRUNE_THRESHOLD && now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            buckets.pruned = now;
+            buckets.entries.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+        let mut tokens = match buckets.entries.get(key) {
+            Some(bucket) => (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity),
+            None => capacity,
+        };
+        let allowed = tokens >= 1.0;
+        if allowed {
+            tokens -= 1.0;
+        }
+        buckets.entries.insert(String::from(key), Bucket { tokens, updated: now });
+        Decision {
+            allowed,
+            limit: self.quota.burst,
+            remaining: tokens.floor().max(0.0) as u32,
+            reset_secs: ((capacity - tokens) / rate).ceil() as u64,
+            retry_after_secs: if allowed { 0 } else { ((1.0 - tokens) / rate).ceil().max(1.0) as u64 },
+        }
+    }
+}
+
+/// 各个路由的限流器，按请求方法和路由模板查找。同一个接口的各个版本共用一个限流器
+#[derive(Debug)]
+pub struct RateLimits {
+    routes: Vec<(Method, &'static str, Arc<RateLimiter>)>,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let mut routes = Vec::new();
+        let limited = [
+            (Method::GET, &["/api/message", "/api/v1/message", "/api/v2/messages"][..], config.get_message),
+            (Method::POST, &["/api/message", "/api/v1/message", "/api/v2/messages"][..], config.post_message),
+            (Method::POST, &["/api/graphql"][..], config.get_message), //GraphQL请求大多是查询，按查询的配额单独计数
+        ];
+        for (method, paths, quota) in limited.iter().filter(|(_, _, quota)| quota.is_enabled()) {
+            let limiter = Arc::new(RateLimiter::new(*quota));
+            routes.extend(paths.iter().map(|path| (method.clone(), *path, limiter.clone())));
+        }
+        RateLimits { routes, trust_forwarded_for: config.trust_forwarded_for }
+    }
+
+    fn limiter(&self, method: &Method, route: &str) -> Option<&RateLimiter> {
+        self.routes
+            .iter()
+            .find(|(limited_method, limited_route, _)| limited_method == method && *limited_route == route)
+            .map(|(_, _, limiter)| limiter.as_ref())
+    }
+
+    /// 检查并扣除一个令牌，这个接口没有限流时返回`None`。GraphQL的变更也用它按对应REST接口的配额计数
+    pub fn check(&self, method: &Method, route: &str, key: &str) -> Option<Decision> {
+        self.limiter(method, route).map(|limiter| limiter.check(key, Instant::now()))
+    }
+
+    /// 按客户端IP限流。`user`cookie没有经过认证，任何人都能冒用，不能用来限流
+    pub fn client_key(&self, request: &HttpRequest) -> String {
+        self.key(&request.connection_info(), request.peer_addr())
+    }
+
+    fn key(&self, connection_info: &ConnectionInfo, peer_addr: Option<SocketAddr>) -> String {
+        let ip = if self.trust_forwarded_for {
+            connection_info.realip_remote_addr().map(|address| match address.parse::<SocketAddr>() {
+                Ok(address) => address.ip().to_string(),
+                Err(_) => String::from(address),
+            }) //没有转发头时退回到连接的地址，里面带着每个连接都不同的端口
+        } else {
+            peer_addr.map(|address| address.ip().to_string())
+        };
+        ip.unwrap_or_default()
+    }
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let values = [
+        ("x-ratelimit-limit", u64::from(decision.limit)),
+        ("x-ratelimit-remaining", u64::from(decision.remaining)),
+        ("x-ratelimit-reset", decision.reset_secs),
+    ];
+    for (name, value) in values.iter() {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(*value));
+    }
+}
+
+pub struct RateLimiting(pub Arc<RateLimits>);
+
+impl<S, B> Transform<S> for RateLimiting
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitingMiddleware { service, limits: self.0.clone() })
+    }
+}
+
+pub struct RateLimitingMiddleware<S> {
+    service: S,
+    limits: Arc<RateLimits>,
+}
+
+impl<S, B> Service for RateLimitingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let route = request.match_pattern();
+        let limiter = match route.as_deref().and_then(|route| self.limits.limiter(request.method(), route)) {
+            Some(limiter) => limiter,
+            None => return Either::Right(Box::pin(self.service.call(request))),
+        };
+        let decision = limiter.check(&self.limits.key(&request.connection_info(), request.peer_addr()), Instant::now());
+        if !decision.allowed {
+            tracing::warn!(path = %request.path(), retry_after = decision.retry_after_secs, "rate limit exceeded");
+            let mut response = ApiError::TooManyRequests(decision.retry_after_secs).error_response();
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(decision.retry_after_secs));
+            set_headers(response.headers_mut(), &decision);
+            return Either::Left(ok(request.into_response(response.into_body())));
+        }
+        let future = self.service.call(request);
+        Either::Right(Box::pin(async move {
+            let mut response = future.await?;
+            set_headers(response.headers_mut(), &decision);
+            Ok(response)
+        }))
+    }
+}
@@ -0,0 +1,79 @@
+use actix_web::{rt, web};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{RunQueryDsl, prelude::*};
+use schemars::JsonSchema;
+use serde::Serialize;
+use crate::Pool;
+use crate::config::RetentionOptions;
+use crate::jobs::Jobs;
+use crate::events::{self, BoardEvent};
+use crate::models::{MessageJson, PostMessage};
+
+/// 一次清理删除了多少条留言
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct PurgeReport {
+    pub expired: usize,
+    pub over_limit: usize,
+}
+
+/// 按保留策略删除留言：先删除早于`now - max_age`的留言，再只保留按时间最新的`keep_newest`条。
+/// 每条被删除的留言都会产生`MessageDeleted`事件
+pub fn apply_retention(
+    db_connection: &SqliteConnection,
+    options: &RetentionOptions,
+    now: NaiveDateTime,
+) -> QueryResult<PurgeReport> {
+    use crate::schema::message::dsl::*;
+    let (report, pending) = db_connection.immediate_transaction::<_, diesel::result::Error, _>(|| {
+        let mut report = PurgeReport::default();
+        let mut purged = Vec::new();
+        if let Some(max_age) = options.max_age {
+            let expired = message.filter(pub_date.lt(now - max_age));
+            purged.extend(expired.load::<PostMessage>(db_connection)?);
+            report.expired = diesel::delete(expired).execute(db_connection)?;
+        }
+        if let Some(keep_newest) = options.keep_newest {
+            let newest = message
+                .select(id)
+                .order((pub_date.desc(), id.desc()))
+                .limit(keep_newest);
+            let over_limit = message.filter(id.ne_all(newest));
+            purged.extend(over_limit.load::<PostMessage>(db_connection)?);
+            report.over_limit = diesel::delete(over_limit).execute(db_connection)?;
+        } //先取出要删除的留言，事件里要带上它们的内容
+        let pending: Vec<BoardEvent> = purged
+            .into_iter()
+            .map(|item| BoardEvent::MessageDeleted { message: MessageJson::from(item) })
+            .collect();
+        events::record_all(db_connection, &pending)?;
+        Ok((report, pending))
+    })?;
+    pending.into_iter().for_each(events::publish);
+    Ok(report)
+}
+
+/// 如果设置了保留策略，就在actix运行时里定时清理过期留言，并报告每次删除了什么
+pub fn spawn_retention_job(jobs: &mut Jobs, pool: Pool, options: RetentionOptions) {
+    if !options.is_enabled() {
+        return;
+    }
+    let period = options.interval;
+    jobs.spawn("retention", rt::time::Instant::now(), period, move || {
+        let pool = pool.clone();
+        let options = options.clone();
+        async move {
+            let result = web::block(move || {
+                let db_connection = pool.get().map_err(|e| e.to_string())?;
+                apply_retention(&db_connection, &options, Utc::now().naive_utc()).map_err(|e| e.to_string())
+            }).await;
+            match result {
+                Ok(report) => tracing::info!(
+                    expired = report.expired,
+                    over_limit = report.over_limit,
+                    "retention purge finished"
+                ),
+                Err(e) => tracing::error!(error = %e, "retention purge failed"),
+            }
+        }
+    });
+}
@@ -1,3 +1,11 @@
+table! {
+    board_revision (id) {
+        id -> Integer,
+        revision -> BigInt,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     message (id) {
         id -> Integer,
@@ -8,6 +16,14 @@ table! {
     }
 }
 
+table! {
+    timestamp_conversion (id) {
+        id -> Integer,
+        source_timezone -> Text,
+        converted_at -> Timestamp,
+    }
+}
+
 table! {
     user (id) {
         id -> Integer,
@@ -16,7 +32,39 @@ table! {
     }
 }
 
+table! {
+    webhook (id) {
+        id -> Integer,
+        url -> Text,
+        secret -> Text,
+        events -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    webhook_delivery (id) {
+        id -> Integer,
+        webhook -> Integer,
+        event -> Text,
+        payload -> Text,
+        status -> Text,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        last_status -> Nullable<Integer>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+joinable!(webhook_delivery -> webhook (webhook));
+
 allow_tables_to_appear_in_same_query!(
+    board_revision,
     message,
+    timestamp_conversion,
     user,
+    webhook,
+    webhook_delivery,
 );
@@ -0,0 +1,68 @@
+use chrono::Utc;
+use diesel::{RunQueryDsl, insert_into, prelude::*};
+use serde::Serialize;
+use crate::board;
+use crate::models::*;
+
+/// 示例数据：(id, 用户名)
+const USERS: [(i32, &str); 2] = [(1, "Alice"), (2, "Bob")];
+/// 示例数据：(id, 作者, 标题, 内容)
+const MESSAGES: [(i32, &str, &str, &str); 2] = [
+    (1, "Alice", "Hi", "Hello, world!"),
+    (2, "Bob", "This is a title", "This is my content"),
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct SeedReport {
+    pub users_created: usize,
+    pub messages_created: usize,
+}
+
+/// 写入Alice和Bob两个用户以及他们的留言，已经存在的不会重复写入。
+/// 尽量使用固定的id，被占用时才分配新的。
+pub fn seed_board(db_connection: &SqliteConnection) -> QueryResult<SeedReport> {
+    db_connection.transaction(|| {
+        let mut report = SeedReport::default();
+        for (user_id, user_name) in USERS.iter() {
+            if board::find_user(db_connection, user_name)?.is_some() {
+                continue;
+            }
+            use crate::schema::user::dsl::*;
+            let free = user.find(*user_id).first::<PostUser>(db_connection).optional()?.is_none();
+            insert_into(user)
+                .values(&PostUser {
+                    id: if free { *user_id } else { board::next_user_id(db_connection)? },
+                    name: String::from(*user_name),
+                    register_date: Utc::now().naive_utc(),
+                })
+                .execute(db_connection)?;
+            report.users_created += 1;
+        }
+        for (message_id, author, message_title, message_content) in MESSAGES.iter() {
+            use crate::schema::message::dsl::*;
+            let author = board::find_user(db_connection, author)?.expect("fixture users were just created").id;
+            let exists = message
+                .filter(user.eq(author))
+                .filter(title.eq(message_title))
+                .filter(content.eq(message_content))
+                .first::<PostMessage>(db_connection)
+                .optional()?
+                .is_some();
+            if exists {
+                continue;
+            }
+            let free = message.find(*message_id).first::<PostMessage>(db_connection).optional()?.is_none();
+            insert_into(message)
+                .values(&PostMessage {
+                    id: if free { *message_id } else { board::next_message_id(db_connection)? },
+                    user: author,
+                    title: String::from(*message_title),
+                    content: String::from(*message_content),
+                    pub_date: Utc::now().naive_utc(),
+                })
+                .execute(db_connection)?;
+            report.messages_created += 1;
+        }
+        Ok(report)
+    })
+}
@@ -1,66 +1,39 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod server_test {
     use core::panic;
 
     use actix_web::{App, dev::{Body, ResponseBody}, http::{Cookie, StatusCode}, test::{self}, web::{self, Bytes}};
-    use dotenv::dotenv;
-    use chrono::Local;
+    use chrono::Utc;
     use crate::Pool;
     use crate::operations;
     use crate::models::*;
     use diesel::{RunQueryDsl, SqliteConnection, prelude::*, r2d2::{ConnectionManager}};
-    use crate::config::ConnectionOptions;
-    fn init_test() -> Pool {
-        use crate::schema::user::dsl::*;
-        dotenv().ok();
-        let database_url = std::env::var("DATABASE_URL")
-            .expect("Unable to locate the database.\nTry setting the 'DATABASE_URL' variable.");
+    use crate::config::{ConnectionOptions, ContentLimits, Synchronous};
+    /// 每个测试用自己的临时数据库，目录在返回的`TempDir`被丢弃时删除
+    fn init_test() -> (tempfile::TempDir, Pool) {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let db_connection = database.get().unwrap();
+        let _ = crate::seed::seed_board(&db_connection); //和`backend-demo seed`写入的是同一份数据
+        (directory, database)
+    }
+    /// 在`directory`下新建一个只有表结构的数据库，不和其他测试共享数据
+    fn init_isolated(directory: &std::path::Path) -> Pool {
+        use diesel::connection::SimpleConnection;
         let database = Pool::builder()
             .max_size(16)
             .connection_customizer(Box::new(ConnectionOptions {
                 enable_wal: true,
+                synchronous: Some(Synchronous::Normal),
                 enable_foreign_keys: false,
                 busy_timeout: Some(std::time::Duration::from_secs(30)),
             }))
-            .build(ConnectionManager::<SqliteConnection>::new(database_url))
+            .build(ConnectionManager::<SqliteConnection>::new(directory.join("isolated.db").to_str().unwrap()))
             .expect("Unable to open the database.");
         let db_connection = database.get().unwrap();
-        let alice = PostUser {
-            id: 1,
-            name: String::from("Alice"),
-            register_date: Local::now().naive_local(),
-        };
-        let bob = PostUser {
-            id: 2,
-            name: String::from("Bob"),
-            register_date: Local::now().naive_local(),
-        };
-        let _ = diesel::insert_into(user)
-            .values(&alice)
-            .execute(&db_connection);
-        let _ = diesel::insert_into(user)
-            .values(&bob)
-            .execute(&db_connection);
-        let hi = PostMessage {
-            id: 1,
-            user: user.filter(name.eq("Alice")).first::<PostUser>(&db_connection).unwrap().id,
-            title: String::from("Hi"),
-            content: String::from("Hello, world!"),
-            pub_date: Local::now().naive_local(),
-        };
-        let this_is_a_title = PostMessage {
-            id: 2,
-            user: user.filter(name.eq("Bob")).first::<PostUser>(&db_connection).unwrap().id,
-            title: String::from("This is a title"),
-            content: String::from("This is my content"),
-            pub_date: Local::now().naive_local(),
-        };
-        let _ = diesel::insert_into(crate::schema::message::dsl::message)
-            .values(&hi)
-            .execute(&db_connection);
-        let _ = diesel::insert_into(crate::schema::message::dsl::message)
-            .values(&this_is_a_title)
-            .execute(&db_connection);
+        crate::embedded_migrations::run(&*db_connection).unwrap();
+        db_connection.batch_execute("PRAGMA foreign_keys = OFF;").unwrap(); //迁移脚本会打开外键检查
         database
     }
     fn end_test(database: Pool) {
@@ -70,14 +43,31 @@ mod server_test {
         let _ = diesel::delete(crate::schema::message::dsl::message)
             .execute(&db_connection);
     }
+    /// 登记一个订阅所有事件的webhook，之后用`queued_events`查看操作产生了哪些事件
+    fn watch_events(db_connection: &SqliteConnection) {
+        use crate::schema::webhook::dsl::*;
+        diesel::insert_into(webhook)
+            .values((
+                url.eq("http://127.0.0.1:9/hook"),
+                secret.eq("s3cret"),
+                events.eq(crate::webhooks::EVENTS.join(",")),
+                created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(db_connection)
+            .unwrap();
+    }
+    fn queued_events(db_connection: &SqliteConnection) -> Vec<String> {
+        use crate::schema::webhook_delivery::dsl::*;
+        webhook_delivery.select(event).order(id).load(db_connection).unwrap()
+    }
 
     #[actix_rt::test]
     async fn test_can_reach() {
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
-            .service(operations::get_message)
+            .route("/api/message", web::get().to(operations::get_message))
         ).await;
         let req = test::TestRequest::get().uri("/api/message").to_request();
         let resp = test::call_service(&mut app, req).await;
@@ -86,12 +76,12 @@ mod server_test {
     #[actix_rt::test]
     async fn test_message_can_be_fetched() {
         use serde_json::json;
-        let database = init_test();
+        let (_directory, database) = init_test();
         let db_connection = database.get().unwrap();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
-            .service(operations::get_message)
+            .route("/api/message", web::get().to(operations::get_message))
         ).await;
         let req = test::TestRequest::get().uri("/api/message").to_request();
         let mut resp = test::call_service(&mut app, req).await;
@@ -105,7 +95,7 @@ mod server_test {
             .load::<PostMessage>(&db_connection)
             .unwrap()
             .into_iter()
-            .map(|x| MessageJson::from(x))
+            .map(MessageJson::from)
             .map(|x| json!(x).to_string())
             .collect();
         end_test(database);
@@ -132,11 +122,12 @@ mod server_test {
     #[actix_rt::test]
     async fn test_add_new_message() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let db_connection = database.get().unwrap();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from("Test title");
@@ -146,10 +137,10 @@ mod server_test {
         struct TempJson {
             title: String,
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
-            content: content,
+            title,
+            content,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -171,16 +162,16 @@ mod server_test {
                 }
             }
         };
-        if let Err(_) = crate::schema::user::dsl::user
+        if crate::schema::user::dsl::user
             .filter(crate::schema::user::dsl::name.eq("Student"))
-            .first::<PostUser>(&db_connection) {
+            .first::<PostUser>(&db_connection).is_err() {
                 end_test(database);
                 panic!("No user named 'Student' found, panicking.");
             }
-        if let Err(_) = crate::schema::message::dsl::message
+        if crate::schema::message::dsl::message
             .filter(crate::schema::message::dsl::title.eq("Test title"))
             .filter(crate::schema::message::dsl::content.eq("My test message"))
-            .first::<PostMessage>(&db_connection) {
+            .first::<PostMessage>(&db_connection).is_err() {
                 end_test(database);
                 panic!("No message found, panicking.");
             } 
@@ -190,10 +181,11 @@ mod server_test {
     #[actix_rt::test]
     async fn test_title_lost() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let content = String::from("My test message");
@@ -201,9 +193,9 @@ mod server_test {
         #[derive(Deserialize, Serialize)]
         struct TempJson {
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            content: content,
+            content,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -214,10 +206,11 @@ mod server_test {
     #[actix_rt::test]
     async fn test_content_lost() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from("Test title");
@@ -225,9 +218,9 @@ mod server_test {
         #[derive(Deserialize, Serialize)]
         struct TempJson {
             title: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
+            title,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -238,10 +231,11 @@ mod server_test {
     #[actix_rt::test]
     async fn test_user_name_too_long() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from("Test title");
@@ -251,10 +245,10 @@ mod server_test {
         struct TempJson {
             title: String,
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
-            content: content,
+            title,
+            content,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -265,10 +259,11 @@ mod server_test {
     #[actix_rt::test]
     async fn test_content_too_long() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from("The Rustonomicon");
@@ -284,10 +279,10 @@ mod server_test {
         struct TempJson {
             title: String,
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
-            content: content,
+            title,
+            content,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -297,10 +292,11 @@ mod server_test {
     #[actix_rt::test]
     async fn test_title_too_long() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from(
@@ -317,10 +313,10 @@ mod server_test {
         struct TempJson {
             title: String,
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
-            content: content,
+            title,
+            content,
         })
         .cookie(Cookie::new("user", user))
         .to_request();
@@ -331,11 +327,12 @@ mod server_test {
     #[actix_rt::test]
     async fn test_unknown_user() {
         use serde::{Deserialize, Serialize};
-        let database = init_test();
+        let (_directory, database) = init_test();
         let db_connection = database.get().unwrap();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
+            .data(ContentLimits::default())
             .route("/api/message", web::post().to(operations::get_post_message))
         ).await;
         let title = String::from("Test title");
@@ -344,10 +341,10 @@ mod server_test {
         struct TempJson {
             title: String,
             content: String,
-        };
+        }
         let req = test::TestRequest::post().uri("/api/message").set_json(&TempJson {
-            title: title,
-            content: content,
+            title,
+            content,
         })
         .to_request();
         let mut resp = test::call_service(&mut app, req).await;
@@ -368,16 +365,16 @@ mod server_test {
                 }
             }
         };
-        if let Err(_) = crate::schema::user::dsl::user
+        if crate::schema::user::dsl::user
             .filter(crate::schema::user::dsl::name.eq("Unknown"))
-            .first::<PostUser>(&db_connection) {
+            .first::<PostUser>(&db_connection).is_err() {
                 end_test(database);
                 panic!("No user named 'Unknown' found, panicking.");
             }
-        if let Err(_) = crate::schema::message::dsl::message
+        if crate::schema::message::dsl::message
             .filter(crate::schema::message::dsl::title.eq("Test title"))
             .filter(crate::schema::message::dsl::content.eq("My test message"))
-            .first::<PostMessage>(&db_connection) {
+            .first::<PostMessage>(&db_connection).is_err() {
                 end_test(database);
                 panic!("No message found, panicking.");
             } 
@@ -388,18 +385,1529 @@ mod server_test {
     #[actix_rt::test]
     async fn test_clear_message() {
         use crate::schema::message::dsl::*;
-        let database = init_test();
+        let (_directory, database) = init_test();
         let db_connection = database.get().unwrap();
         let mut app = test::init_service(
             App::new()
             .data(database.clone())
-            .service(operations::clear_message)
+            .route("/api/clearmessage", web::get().to(operations::clear_message))
         ).await;
         let req = test::TestRequest::get().uri("/api/clearmessage").to_request();
         let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
-        if let Ok(_) = message.first::<PostMessage>(&db_connection) {
+        if message.first::<PostMessage>(&db_connection).is_ok() {
             panic!("Database is not cleared!");
         }
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn test_backup_snapshot() {
+        use crate::{admin, backup, config::BackupOptions};
+        let (database_directory, database) = init_test();
+        let directory = tempfile::tempdir().unwrap();
+        let options = BackupOptions {
+            database_url: database_directory.path().join("isolated.db").to_str().unwrap().to_string(),
+            directory: directory.path().to_path_buf(),
+            interval: None,
+            keep: 2,
+        };
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(options)
+            .service(admin::create_backup)
+        ).await;
+        for _ in 0..3 {
+            let req = test::TestRequest::post().uri("/api/admin/backup").to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+        let snapshots: Vec<_> = std::fs::read_dir(directory.path()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(snapshots.len(), 2); //只保留最新的两份
+        for snapshot in &snapshots {
+            assert!(backup::validate_backup(snapshot).is_ok());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_restore_validates_backup() {
+        use crate::backup;
+        let directory = tempfile::tempdir().unwrap();
+        let snapshot = directory.path().join("snapshot.db");
+        let target = directory.path().join("target.db");
+        let target = target.to_str().unwrap();
+        let _source = init_isolated(directory.path());
+        backup::backup_database(directory.path().join("isolated.db").to_str().unwrap(), &snapshot).unwrap();
+        let garbage = directory.path().join("garbage.db");
+        std::fs::write(&garbage, "this is not a database").unwrap();
+        assert!(backup::restore_database(target, &garbage).is_err());
+        assert!(backup::restore_database(target, &directory.path().join("missing.db")).is_err());
+        backup::restore_database(target, &snapshot).unwrap();
+        assert!(backup::validate_backup(std::path::Path::new(target)).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_export_and_import() {
+        use crate::admin;
+        let source_directory = tempfile::tempdir().unwrap();
+        let source = init_isolated(source_directory.path());
+        {
+            let db_connection = source.get().unwrap();
+            diesel::insert_into(crate::schema::user::dsl::user)
+                .values(&PostUser { id: 1, name: String::from("Carol"), register_date: Utc::now().naive_utc() })
+                .execute(&db_connection)
+                .unwrap();
+            diesel::insert_into(crate::schema::message::dsl::message)
+                .values(&PostMessage {
+                    id: 7,
+                    user: 1,
+                    title: String::from("Moving"),
+                    content: String::from("See you on the other side"),
+                    pub_date: Utc::now().naive_utc(),
+                })
+                .execute(&db_connection)
+                .unwrap();
+        }
+        let mut app = test::init_service(
+            App::new()
+            .data(source.clone())
+            .service(admin::export_board)
+        ).await;
+        let req = test::TestRequest::get().uri("/api/admin/export").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let exported = test::read_body(resp).await;
+        assert_eq!(exported.split(|b| *b == b'\n').filter(|line| !line.is_empty()).count(), 2);
+
+        let target_directory = tempfile::tempdir().unwrap();
+        let target = init_isolated(target_directory.path());
+        diesel::insert_into(crate::schema::user::dsl::user)
+            .values(&PostUser { id: 5, name: String::from("Carol"), register_date: Utc::now().naive_utc() })
+            .execute(&target.get().unwrap())
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+            .data(target.clone())
+            .data(ContentLimits::default())
+            .route("/api/admin/import", web::post().to(admin::import_board))
+        ).await;
+        let mut payload = exported.to_vec();
+        payload.extend_from_slice(b"{\"id\": 8, \"user\": 42, \"title\": \"t\", \"content\": \"c\", \"pub_date\": \"2021-03-12 02:07:06\"}\nnot json\n");
+        watch_events(&target.get().unwrap());
+        let req = test::TestRequest::post().uri("/api/admin/import?dry_run=true").set_payload(payload.clone()).to_request();
+        let report: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(report["messages_created"], 1);
+        assert_eq!(report["errors"].as_array().unwrap().len(), 2);
+        assert!(crate::schema::message::dsl::message
+            .first::<PostMessage>(&target.get().unwrap())
+            .is_err()); //dry run不应写入任何数据
+        assert!(queued_events(&target.get().unwrap()).is_empty());
+        let req = test::TestRequest::post().uri("/api/admin/import").set_payload(payload).to_request();
+        let report: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(report["users_matched"], 1);
+        assert_eq!(report["errors"][0]["line"], 3);
+        let imported = crate::schema::message::dsl::message
+            .find(7)
+            .first::<PostMessage>(&target.get().unwrap())
+            .unwrap();
+        assert_eq!(imported.user, 5); //作者按名字重新映射
+        assert_eq!(queued_events(&target.get().unwrap()), ["message.created"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_timestamps_in_requested_zone() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let published = chrono::NaiveDate::from_ymd(2021, 3, 12).and_hms(2, 7, 6);
+        diesel::insert_into(crate::schema::message::dsl::message)
+            .values(&PostMessage {
+                id: 1,
+                user: 1,
+                title: String::from("Hi"),
+                content: String::from("Hello, world!"),
+                pub_date: published,
+            })
+            .execute(&database.get().unwrap())
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .route("/api/message", web::get().to(operations::get_message))
+        ).await;
+        for (tz, expected) in &[
+            ("", "2021-03-12T02:07:06+00:00"),
+            ("?tz=%2B08:00", "2021-03-12T10:07:06+08:00"),
+            ("?tz=America/New_York", "2021-03-11T21:07:06-05:00"),
+        ] {
+            let req = test::TestRequest::get().uri(&format!("/api/message{}", tz)).to_request();
+            let body: Vec<String> = test::read_response_json(&mut app, req).await;
+            let message: MessageJson = serde_json::from_str(&body[0]).unwrap();
+            assert_eq!(&message.pub_date, expected);
+        }
+        let req = test::TestRequest::get().uri("/api/message?tz=Mars/Olympus").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_convert_timestamps_to_utc() {
+        use crate::timezone::{self, Zone};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let db_connection = database.get().unwrap();
+        let local = chrono::NaiveDate::from_ymd(2021, 3, 12).and_hms(10, 7, 6);
+        diesel::insert_into(crate::schema::user::dsl::user)
+            .values(&PostUser { id: 1, name: String::from("Alice"), register_date: local })
+            .execute(&db_connection)
+            .unwrap();
+        diesel::delete(crate::schema::timestamp_conversion::table).execute(&db_connection).unwrap(); //模拟旧版本留下的数据
+        assert!(timezone::conversion_pending(&db_connection).unwrap());
+        let zone = Zone::parse("Asia/Shanghai").unwrap();
+        assert_eq!(timezone::convert_to_utc(&db_connection, &zone, "Asia/Shanghai").unwrap(), 1);
+        let alice = crate::schema::user::dsl::user.find(1).first::<PostUser>(&db_connection).unwrap();
+        assert_eq!(alice.register_date, chrono::NaiveDate::from_ymd(2021, 3, 12).and_hms(2, 7, 6));
+        assert!(timezone::convert_to_utc(&db_connection, &zone, "Asia/Shanghai").is_err()); //只能转换一次
+    }
+
+    #[actix_rt::test]
+    async fn test_retention_purge() {
+        use crate::{admin, config::RetentionOptions};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        for (message_id, days_ago) in &[(1, 40), (2, 20), (3, 10), (4, 5), (5, 1)] {
+            diesel::insert_into(crate::schema::message::dsl::message)
+                .values(&PostMessage {
+                    id: *message_id,
+                    user: 1,
+                    title: String::from("Old news"),
+                    content: format!("Posted {} days ago", days_ago),
+                    pub_date: Utc::now().naive_utc() - chrono::Duration::days(*days_ago),
+                })
+                .execute(&database.get().unwrap())
+                .unwrap();
+        }
+        watch_events(&database.get().unwrap());
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(RetentionOptions {
+                max_age: Some(chrono::Duration::days(30)),
+                keep_newest: Some(2),
+                interval: std::time::Duration::from_secs(3600),
+            })
+            .service(admin::run_retention)
+        ).await;
+        let req = test::TestRequest::post().uri("/api/admin/retention").to_request();
+        let report: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(report["expired"], 1);
+        assert_eq!(report["over_limit"], 2);
+        let remaining: Vec<i32> = crate::schema::message::dsl::message
+            .select(crate::schema::message::dsl::id)
+            .order(crate::schema::message::dsl::id)
+            .load(&database.get().unwrap())
+            .unwrap();
+        assert_eq!(remaining, vec![4, 5]);
+        assert_eq!(queued_events(&database.get().unwrap()), ["message.deleted"; 3]); //每条被清理的留言都有事件
+    }
+
+    #[actix_rt::test]
+    async fn test_maintenance_finds_and_fixes_problems() {
+        use crate::admin;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let db_connection = database.get().unwrap();
+        for user_id in 1..=2 {
+            diesel::insert_into(crate::schema::user::dsl::user)
+                .values(&PostUser { id: user_id, name: String::from("Alice"), register_date: Utc::now().naive_utc() })
+                .execute(&db_connection)
+                .unwrap();
+        }
+        for (message_id, author) in &[(1, 2), (2, 9)] {
+            diesel::insert_into(crate::schema::message::dsl::message)
+                .values(&PostMessage {
+                    id: *message_id,
+                    user: *author,
+                    title: String::from("Hi"),
+                    content: String::from("Hello, world!"),
+                    pub_date: Utc::now().naive_utc(),
+                })
+                .execute(&db_connection)
+                .unwrap();
+        }
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .service(admin::run_maintenance)
+        ).await;
+        let req = test::TestRequest::post().uri("/api/admin/maintenance").to_request();
+        let report: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(report["integrity"], serde_json::json!(["ok"]));
+        assert_eq!(report["foreign_key_violations"].as_array().unwrap().len(), 1);
+        assert_eq!(report["orphaned_messages"], serde_json::json!([2]));
+        assert_eq!(report["duplicate_users"][0]["ids"], serde_json::json!([1, 2]));
+        watch_events(&db_connection);
+        let req = test::TestRequest::post().uri("/api/admin/maintenance?fix=true&vacuum=true").to_request();
+        let report: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(report["fixed"]["merged_users"], 1);
+        assert_eq!(report["fixed"]["reassigned_messages"], 2);
+        assert_eq!(report["orphaned_messages"], serde_json::json!([]));
+        assert_eq!(report["duplicate_users"], serde_json::json!([]));
+        assert_eq!(report["vacuumed"], true);
+        let authors: Vec<i32> = crate::schema::message::dsl::message
+            .select(crate::schema::message::dsl::user)
+            .order(crate::schema::message::dsl::id)
+            .load(&db_connection)
+            .unwrap();
+        assert_eq!(authors, vec![1, 2]); //合并后id为2的用户已被删除，孤立的留言归到新建的Unknown用户
+        assert_eq!(queued_events(&db_connection), ["user.created", "message.updated", "message.updated"]);
+    }
+
+    #[test]
+    fn test_seed_and_manage_board() {
+        use crate::{board, seed};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let db_connection = database.get().unwrap();
+        let report = seed::seed_board(&db_connection).unwrap();
+        assert_eq!((report.users_created, report.messages_created), (2, 2));
+        let report = seed::seed_board(&db_connection).unwrap();
+        assert_eq!((report.users_created, report.messages_created), (0, 0)); //重复执行不会写入重复数据
+        let users = board::list_users(&db_connection).unwrap();
+        assert_eq!(
+            users.iter().map(|(item, count)| (item.id, item.name.as_str(), *count)).collect::<Vec<_>>(),
+            vec![(1, "Alice", 1), (2, "Bob", 1)]
+        );
+        let bob = board::find_user(&db_connection, "Bob").unwrap().unwrap();
+        assert_eq!(board::delete_user(&db_connection, &bob, false).unwrap(), None); //还有留言的用户默认不删除
+        board::rename_user(&db_connection, &bob, "Robert").unwrap();
+        let messages = board::list_messages(&db_connection, Some(bob.id), 100, 0).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1.as_deref(), Some("Robert"));
+        assert_eq!(board::delete_user(&db_connection, &bob, true).unwrap(), Some(1));
+        assert_eq!(board::delete_message(&db_connection, 1).unwrap(), 1);
+        assert_eq!(board::list_messages(&db_connection, None, 100, 0).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_cli_changes_queue_events() {
+        use crate::cli::{self, MessageCommand, UserCommand};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let db_connection = database.get().unwrap();
+        crate::seed::seed_board(&db_connection).unwrap();
+        watch_events(&db_connection);
+        let limits = ContentLimits::default();
+        cli::manage_users(&database, UserCommand::Create { name: String::from("Carol") }, &limits).unwrap();
+        cli::manage_messages(&database, MessageCommand::Delete { id: 1 }).unwrap();
+        cli::manage_users(&database, UserCommand::Delete { name: String::from("Bob"), with_messages: true }, &limits).unwrap();
+        cli::clear_messages(&database).unwrap();
+        assert_eq!(
+            queued_events(&db_connection),
+            vec!["user.created", "message.deleted", "message.deleted", "board.cleared"]
+        ); //和接口一样写入投递队列，服务器运行时由webhook任务发出
+    }
+
+    #[test]
+    fn test_config_layers() {
+        use crate::config::{Config, ConfigOverrides};
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("backend.toml");
+        std::fs::write(&file, "
+            database_url = \"layered.db\"
+            [pool]
+            max_size = 4
+            [limits]
+            max_title_length = 50
+            max_content_length = 300
+        ").unwrap();
+        let env = |key: &str| match key {
+            "MAX_CONTENT_LENGTH" => Some(String::from("350")),
+            "POOL_MAX_SIZE" => Some(String::from("5")),
+            _ => None,
+        }; //不改动进程的环境变量，其他测试也在同时读取配置
+        let overrides = ConfigOverrides {
+            pool_max_size: Some(6),
+            listen: vec![String::from("0.0.0.0:8080"), String::from("[::1]:8080")],
+            ..Default::default()
+        };
+        let config = Config::load_with(Some(&file), &overrides, &env).unwrap();
+        assert_eq!(config.limits.max_title_length, 50); //只在配置文件中设置
+        assert_eq!(config.limits.max_content_length, 350); //环境变量覆盖配置文件
+        assert_eq!(config.pool.max_size, 6); //命令行参数覆盖环境变量
+        assert_eq!(config.limits.max_name_length, 20); //默认值
+        assert_eq!(config.server.listen.len(), 2);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        use crate::config::{Config, ConfigOverrides};
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("backend.toml");
+        std::fs::write(&file, "
+            database_url = \"invalid.db\"
+            [server]
+            listen = [\"localhost\"]
+            workers = 0
+            [pool]
+            max_size = 2
+            min_idle = 3
+            [timestamps]
+            source_timezone = \"Mars/Olympus\"
+        ").unwrap();
+        let no_env = |_: &str| None;
+        let errors = Config::load_with(Some(&file), &ConfigOverrides::default(), &no_env).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        std::fs::write(&file, "[pool]\nmax_sizes = 2\n").unwrap();
+        assert!(Config::load_with(Some(&file), &ConfigOverrides::default(), &no_env).is_err()); //拼错的配置项也会报错
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_header() {
+        use crate::logging;
+        let (_directory, database) = init_test();
+        let mut app = test::init_service(
+            App::new()
+            .wrap(logging::RequestTracing)
+            .data(database.clone())
+            .route("/api/message", web::get().to(operations::get_message))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message").header("X-Request-Id", "abc-123").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get(logging::REQUEST_ID_HEADER).unwrap(), "abc-123"); //沿用传入的请求id
+        let req = test::TestRequest::get().uri("/api/message").header("X-Request-Id", "bad id").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let generated = resp.headers().get(logging::REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+        assert_eq!(generated.len(), 36); //不合法时生成新的UUID
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_endpoint() {
+        use crate::metrics;
+        let (_directory, database) = init_test();
+        let mut app = test::init_service(
+            App::new()
+            .wrap(metrics::RequestMetrics)
+            .data(database.clone())
+            .service(metrics::export_metrics)
+            .route("/api/message", web::get().to(operations::get_message))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message?limit=1").to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::read_response(&mut app, req).await.to_vec()).unwrap();
+        assert!(body.contains(r#"http_requests_total{method="GET",route="/api/message",status="200"}"#));
+        assert!(body.contains("http_request_duration_seconds_bucket"));
+        assert!(body.contains("db_query_duration_seconds_count{query=\"list_messages\"}"));
+        assert!(body.contains("db_pool_max_size 16"));
+    }
+
+    #[actix_rt::test]
+    async fn test_health_endpoints() {
+        use crate::health;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .service(health::liveness)
+            .service(health::readiness)
+        ).await;
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(report["checks"]["migrations"]["applied"], crate::SCHEMA_VERSION);
+        assert_eq!(report["checks"]["wal"]["journal_mode"], "wal");
+        let db_connection = database.get().unwrap();
+        diesel::sql_query("DELETE FROM __diesel_schema_migrations WHERE version = (SELECT MAX(version) FROM __diesel_schema_migrations)")
+            .execute(&db_connection)
+            .unwrap();
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE); //还有没执行的迁移
+        let report: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(report["checks"]["migrations"]["ok"], false);
+    }
+
+    #[test]
+    fn test_schema_version_is_latest_migration() {
+        let latest = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .max()
+            .unwrap();
+        let version: String = latest.split('_').next().unwrap().chars().filter(char::is_ascii_digit).collect();
+        assert_eq!(version, crate::SCHEMA_VERSION); //添加迁移后要更新SCHEMA_VERSION
+    }
+
+    #[test]
+    fn test_final_checkpoint_truncates_wal() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        crate::seed::seed_board(&database.get().unwrap()).unwrap();
+        let wal = directory.path().join("isolated.db-wal");
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        crate::maintenance::final_checkpoint(&database);
+        assert_eq!(std::fs::metadata(&wal).map(|m| m.len()).unwrap_or(0), 0); //WAL已合并回数据库文件
+    }
+
+    #[test]
+    fn test_jobs_stop() {
+        actix_web::rt::System::new("test_jobs_stop").block_on(jobs_stop());
+    }
+
+    async fn jobs_stop() {
+        use std::{cell::Cell, rc::Rc, time::Duration};
+        use actix_web::rt::time::{Instant, delay_for};
+        let ticks = Rc::new(Cell::new(0));
+        let counter = ticks.clone();
+        let mut jobs = crate::jobs::Jobs::default();
+        jobs.spawn("test", Instant::now(), Duration::from_millis(10), move || {
+            counter.set(counter.get() + 1);
+            async {}
+        });
+        delay_for(Duration::from_millis(50)).await;
+        jobs.stop().await;
+        assert!(ticks.get() > 0);
+        assert_eq!(Rc::strong_count(&ticks), 1); //任务退出时释放了它持有的资源
+        let stopped = ticks.get();
+        delay_for(Duration::from_millis(30)).await;
+        assert_eq!(ticks.get(), stopped);
+    }
+
+    #[actix_rt::test]
+    async fn test_https_redirect() {
+        use actix_web::http::header;
+        use crate::tls;
+        let mut app = test::init_service(
+            App::new()
+            .data(tls::HttpsPort(8443))
+            .default_service(web::route().to(tls::redirect_to_https))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message?limit=1").header("Host", "example.com:8080").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "https://example.com:8443/api/message?limit=1");
+        assert!(tls::load_certified_key(std::path::Path::new("missing.pem"), std::path::Path::new("missing.key")).is_err());
+    }
+
+    #[test]
+    fn test_certificate_reload() {
+        actix_web::rt::System::new("test_certificate_reload").block_on(certificate_reload());
+    }
+
+    /// 用`testdata/tls/ca.pem`验证证书，完成一次TLS握手，返回服务器出示的证书
+    fn served_certificate(address: std::net::SocketAddr) -> Vec<u8> {
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let mut config = rustls::ClientConfig::new();
+        let ca = std::fs::File::open(fixtures.join("ca.pem")).unwrap();
+        config.root_store.add_pem_file(&mut std::io::BufReader::new(ca)).unwrap();
+        let hostname = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = rustls::ClientSession::new(&std::sync::Arc::new(config), hostname);
+        let mut socket = std::net::TcpStream::connect(address).unwrap();
+        while rustls::Session::is_handshaking(&session) {
+            rustls::Session::complete_io(&mut session, &mut socket).unwrap();
+        }
+        rustls::Session::get_peer_certificates(&session).unwrap()[0].0.clone()
+    }
+
+    async fn certificate_reload() {
+        use actix_web::HttpResponse;
+        use crate::tls;
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let directory = tempfile::tempdir().unwrap();
+        let (cert, key) = (directory.path().join("cert.pem"), directory.path().join("key.pem"));
+        let install = |name: &str| {
+            std::fs::copy(fixtures.join(format!("{}.pem", name)), &cert).unwrap();
+            std::fs::copy(fixtures.join(format!("{}-key.pem", name)), &key).unwrap();
+            tls::load_certified_key(&cert, &key).unwrap().cert[0].0.clone()
+        }; //换上另一张证书，返回它的DER
+        let first = install("a");
+        let certificates = tls::ReloadableCert::load(&cert, &key).unwrap();
+        let server = test::start_with(test::config().rustls(tls::server_config(certificates.clone())), || {
+            App::new().route("/", web::get().to(HttpResponse::Ok))
+        });
+        assert_eq!(served_certificate(server.addr()), first);
+        let second = install("b");
+        assert_ne!(first, second);
+        assert_eq!(served_certificate(server.addr()), first); //收到SIGHUP之前继续使用旧证书
+        certificates.reload().unwrap();
+        assert_eq!(served_certificate(server.addr()), second);
+        std::fs::write(&cert, "not a certificate").unwrap();
+        assert!(certificates.reload().is_err());
+        assert_eq!(served_certificate(server.addr()), second); //新证书有问题时保留原来的
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limits() {
+        use std::time::{Duration, Instant};
+        use crate::config::{Quota, RateLimitConfig};
+        use crate::ratelimit::{RateLimiter, RateLimiting, RateLimits};
+        let limiter = RateLimiter::new(Quota { burst: 2, per_minute: 60 });
+        let start = Instant::now();
+        assert!(limiter.check("10.0.0.1", start).allowed);
+        assert!(limiter.check("10.0.0.1", start).allowed);
+        let limited = limiter.check("10.0.0.1", start);
+        assert!(!limited.allowed);
+        assert_eq!((limited.remaining, limited.retry_after_secs, limited.reset_secs), (0, 1, 2));
+        assert!(limiter.check("10.0.0.2", start).allowed); //每个IP各有一个桶
+        assert!(limiter.check("10.0.0.1", start + Duration::from_secs(1)).allowed); //每秒恢复一个令牌
+
+        let (_directory, database) = init_test();
+        let limits = std::sync::Arc::new(RateLimits::new(&RateLimitConfig {
+            get_message: Quota { burst: 1, per_minute: 1 },
+            post_message: Quota { burst: 0, per_minute: 0 },
+            trust_forwarded_for: false,
+        }));
+        let mut app = test::init_service(
+            App::new()
+            .wrap(RateLimiting(limits))
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .route("/api/message", web::get().to(operations::get_message))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message?limit=1").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        let req = test::TestRequest::get().uri("/api/message?limit=1").cookie(Cookie::new("user", "Alice")).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS); //换一个user cookie也还是同一个IP
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "60");
+        assert_eq!(resp.headers().get("x-ratelimit-limit").unwrap(), "1");
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["code"], "rate_limited");
+
+        let forwarded = std::sync::Arc::new(RateLimits::new(&RateLimitConfig {
+            get_message: Quota { burst: 1, per_minute: 1 },
+            post_message: Quota { burst: 0, per_minute: 0 },
+            trust_forwarded_for: true,
+        }));
+        let mut app = test::init_service(
+            App::new()
+            .wrap(RateLimiting(forwarded))
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .route("/api/message", web::get().to(operations::get_message))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message?limit=1").header("X-Forwarded-For", "203.0.113.5").peer_addr("10.0.0.7:4000".parse().unwrap()).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/api/message?limit=1").peer_addr("10.0.0.7:4001".parse().unwrap()).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK); //按转发头里的地址计数，和代理自己的地址分开
+        let req = test::TestRequest::get().uri("/api/message?limit=1").peer_addr("10.0.0.7:4002".parse().unwrap()).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::TOO_MANY_REQUESTS); //没有转发头时不带端口，同一个IP的连接共用一个桶
+    }
+
+    #[actix_rt::test]
+    async fn test_cors_policies() {
+        use actix_web::HttpResponse;
+        use crate::admin;
+        use crate::config::{CorsConfig, CorsRouteConfig};
+        use crate::cors::{Cors, CorsPolicies};
+        let (_directory, database) = init_test();
+        let handled = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let probe = {
+            let handled = handled.clone();
+            move || {
+                handled.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                HttpResponse::Ok()
+            }
+        };
+        let policies = CorsPolicies::new(&CorsConfig {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            allow_credentials: true,
+            routes: vec![
+                CorsRouteConfig { path: String::from("/api/admin"), allowed_origins: Some(vec![String::from("*")]), ..Default::default() },
+                CorsRouteConfig { path: String::from("/metrics"), allowed_origins: Some(Vec::new()), ..Default::default() },
+            ],
+            ..Default::default()
+        });
+        assert!(policies.policy_for("/metrics").is_none()); //覆盖规则关闭了这个路径的跨域
+        let mut app = test::init_service(
+            App::new()
+            .wrap(Cors(std::sync::Arc::new(policies)))
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .route("/api/message", web::get().to(operations::get_message))
+            .route("/api/message", web::post().to(operations::get_post_message))
+            .service(admin::run_maintenance)
+            .route("/api/admin/probe", web::post().to(probe.clone()))
+            .route("/api/v2/messages", web::delete().to(probe))
+        ).await;
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/message")
+            .header("Origin", "https://app.example.com")
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "Content-Type")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "https://app.example.com");
+        assert_eq!(resp.headers().get("access-control-allow-credentials").unwrap(), "true");
+        assert_eq!(resp.headers().get("access-control-max-age").unwrap(), "3600");
+        let req = test::TestRequest::get()
+            .uri("/api/message?limit=1")
+            .header("Origin", "https://evil.example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.headers().get("access-control-allow-origin").is_none()); //不在名单里的来源
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/admin/maintenance")
+            .header("Origin", "https://app.example.com")
+            .header("Access-Control-Request-Method", "POST")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN); //管理接口即使配置了也不允许跨域
+        for req in [
+            test::TestRequest::post().uri("/api/admin/probe").header("Origin", "https://app.example.com"),
+            test::TestRequest::delete().uri("/api/v2/messages").header("Origin", "https://evil.example.com"),
+        ] {
+            let resp = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let error: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(error["code"], "forbidden");
+        }
+        assert_eq!(handled.load(std::sync::atomic::Ordering::SeqCst), 0); //跨域的管理请求不会执行
+        let resp = test::call_service(&mut app, test::TestRequest::post().uri("/api/admin/probe").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK); //同源请求不受影响
+        let req = test::TestRequest::post()
+            .uri("/api/admin/probe")
+            .header("Host", "board.example.com")
+            .header("Origin", "http://board.example.com")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK); //浏览器给同源的POST也会带上Origin
+        assert_eq!(handled.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_post_message_body_handling() {
+        use actix_web::http::header;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let limits = ContentLimits { message_payload_bytes: 64, ..Default::default() };
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(limits)
+            .service(
+                web::resource("/api/message")
+                    .app_data(web::PayloadConfig::new(limits.message_payload_bytes))
+                    .route(web::post().to(operations::get_post_message))
+            )
+        ).await;
+        let post = |content_type: &str, body: &[u8]| {
+            test::TestRequest::post()
+                .uri("/api/message")
+                .header(header::CONTENT_TYPE, content_type)
+                .cookie(Cookie::new("user", "Carol"))
+                .set_payload(body.to_vec())
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, post("application/x-www-form-urlencoded", b"title=Hi&content=from+a+form")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED); //表单也可以发帖
+        let resp = test::call_service(&mut app, post("text/plain", b"title=Hi")).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let resp = test::call_service(&mut app, post("application/json", &[b'{', 0xff, b'}'])).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["message"], "Request body is not valid UTF-8 (invalid byte at offset 1)");
+        let resp = test::call_service(&mut app, post("application/json", b"{\"title\": \"Hi\",\n \"content\": }")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert!(error["message"].as_str().unwrap().starts_with("Invalid JSON at line 2 column"));
+        let resp = test::call_service(&mut app, post("application/json", &[b' '; 100])).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let users: i64 = crate::schema::user::dsl::user.count().get_result(&database.get().unwrap()).unwrap();
+        assert_eq!(users, 1); //出错的请求不会创建用户
+    }
+
+    #[actix_rt::test]
+    async fn test_api_error_body() {
+        use crate::logging;
+        let (_directory, database) = init_test();
+        let mut app = test::init_service(
+            App::new()
+            .wrap(logging::RequestTracing)
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .route("/api/message", web::get().to(operations::get_message))
+            .service(web::resource("/api/message").route(web::post().to(operations::get_post_message)))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message?limit=abc").header("X-Request-Id", "err-1").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error, serde_json::json!({
+            "code": "invalid_field",
+            "message": "abc is not a number",
+            "field": "limit",
+            "request_id": "err-1",
+        }));
+        let req = test::TestRequest::post()
+            .uri("/api/message")
+            .set_json(&ReceiveMessageJson { title: "t".repeat(MAX_TITLE_LENGTH + 1), content: String::new() })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["field"], "title");
+        assert_eq!(error["request_id"].as_str().map(str::len), Some(36)); //生成的请求id也会写进错误响应
+
+        use crate::{admin, config::{BackupOptions, RetentionOptions}};
+        let directory = tempfile::tempdir().unwrap();
+        let mut app = test::init_service(
+            App::new()
+            .wrap(logging::RequestTracing)
+            .data(database.clone())
+            .data(BackupOptions {
+                database_url: directory.path().join("missing").join("backend.db").to_str().unwrap().to_string(),
+                directory: directory.path().to_path_buf(),
+                interval: None,
+                keep: 1,
+            })
+            .data(RetentionOptions { max_age: None, keep_newest: None, interval: std::time::Duration::from_secs(3600) })
+            .service(admin::create_backup)
+            .service(admin::run_retention)
+        ).await;
+        let req = test::TestRequest::post().uri("/api/admin/backup").header("X-Request-Id", "err-2").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error, serde_json::json!({
+            "code": "database_error",
+            "message": "Database error",
+            "field": null,
+            "request_id": "err-2",
+        })); //文件路径之类的细节只写日志
+        let req = test::TestRequest::post().uri("/api/admin/retention").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["code"], "conflict");
+    }
+
+    /// 用每个方法请求`route_table`里的每个路径，不是404也不是405的就是注册了的接口。
+    /// 没有配置连接池，处理函数在取数据库时就会失败，不会真的执行
+    async fn registered_operations() -> std::collections::BTreeSet<(String, String)> {
+        use actix_web::{HttpResponse, http::Method};
+        let paths: std::collections::BTreeSet<&str> = crate::route_table(ContentLimits::default(), Default::default())
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        let mut app = test::init_service(
+            App::new()
+            .default_service(web::to(|| HttpResponse::build(StatusCode::IM_A_TEAPOT))) //和处理函数返回的404区分开
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), Default::default()))
+        ).await;
+        let mut operations = std::collections::BTreeSet::new();
+        for path in paths {
+            let mut found = false;
+            for method in [Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+                let uri = path.replace(['{', '}'], ""); //路径参数用参数名代替
+                let req = test::TestRequest::default().method(method.clone()).uri(&uri).to_request();
+                let resp = test::call_service(&mut app, req).await;
+                if resp.status() != StatusCode::IM_A_TEAPOT && resp.status() != StatusCode::METHOD_NOT_ALLOWED {
+                    operations.insert((method.as_str().to_ascii_lowercase(), String::from(path)));
+                    found = true;
+                }
+            }
+            assert!(found, "{} in the route table does not match the path it registers", path);
+        }
+        operations
+    }
+
+    #[actix_rt::test]
+    async fn test_openapi_matches_routes() {
+        let spec = crate::openapi::spec();
+        let documented: std::collections::BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| item.as_object().unwrap().keys().map(move |method| (method.clone(), path.clone())))
+            .collect();
+        assert_eq!(documented, registered_operations().await); //新增或删除接口时要同时更新`openapi::spec`
+        for name in ["MessageJson", "ReceiveMessageJson", "UserJson", "ErrorBody"] {
+            assert!(spec["components"]["schemas"][name].is_object(), "{} is missing", name);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_openapi_schemas_match_responses() {
+        let (_directory, database) = init_test();
+        let spec = crate::openapi::spec();
+        let properties = |name: &str| -> std::collections::BTreeSet<String> {
+            spec["components"]["schemas"][name]["properties"].as_object().unwrap().keys().cloned().collect()
+        };
+        let mut app = test::init_service(
+            App::new()
+            .wrap(crate::logging::RequestTracing)
+            .data(database.clone())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), Default::default()))
+        ).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/message?limit=1").to_request()).await;
+        let items: Vec<String> = test::read_body_json(resp).await;
+        let item: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&items[0]).unwrap();
+        assert_eq!(item.keys().cloned().collect::<std::collections::BTreeSet<_>>(), properties("MessageJson"));
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v2/messages?limit=x").to_request()).await;
+        let error: serde_json::Map<String, serde_json::Value> = test::read_body_json(resp).await;
+        assert_eq!(error.keys().cloned().collect::<std::collections::BTreeSet<_>>(), properties("ErrorBody"));
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/openapi.json").to_request()).await;
+        let served: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(served, spec);
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/docs").to_request()).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/html; charset=utf-8");
+    }
+
+    #[actix_rt::test]
+    async fn test_api_versions() {
+        use crate::config::ApiConfig;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let limits = ContentLimits::default();
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(limits)
+            .configure(|routes| crate::register_routes(routes, limits, ApiConfig::default()))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/api/v2/messages")
+            .cookie(Cookie::new("user", "Dave"))
+            .set_json(&ReceiveMessageJson { title: String::from("v2"), content: String::from("clean") })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: MessageJson = test::read_body_json(resp).await; //v2返回保存后的留言
+        assert_eq!(created.title, "v2");
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v2/messages").to_request()).await;
+        assert!(resp.headers().get("deprecation").is_none());
+        let listed: Vec<MessageJson> = test::read_body_json(resp).await; //不再是编码两次的字符串
+        assert_eq!(listed[0].id, created.id);
+        for uri in ["/api/message", "/api/v1/message"] {
+            let resp = test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.headers().get("deprecation").unwrap(), "true");
+            assert_eq!(resp.headers().get("link").unwrap(), "</api/v2/messages>; rel=\"successor-version\"");
+            let listed: Vec<String> = test::read_body_json(resp).await;
+            assert_eq!(serde_json::from_str::<MessageJson>(&listed[0]).unwrap().id, created.id);
+        }
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v1/message?limit=x").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(test::read_body(resp).await, "x is not a number"); //v1默认和原来一样返回纯文本错误
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v2/messages?limit=x").to_request()).await;
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["field"], "limit");
+        let resp = test::call_service(&mut app, test::TestRequest::delete().uri("/api/v2/messages").to_request()).await;
+        let deleted: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(deleted["deleted"], 1);
+
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(limits)
+            .configure(|routes| crate::register_routes(routes, limits, ApiConfig { django_compat: false, ..Default::default() }))
+        ).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/message?limit=x").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["code"], "invalid_field"); //关掉兼容模式后v1也返回JSON错误
+    }
+
+    #[actix_rt::test]
+    async fn test_conditional_listing() {
+        use std::time::Duration;
+        use actix_web::http::header::HttpDate;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let limits = ContentLimits::default();
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(limits)
+            .configure(|routes| crate::register_routes(routes, limits, Default::default()))
+        ).await;
+        let get = |uri: &str, header: Option<(&str, &str)>| {
+            let request = test::TestRequest::get().uri(uri);
+            match header {
+                Some((name, value)) => request.header(name, value).to_request(),
+                None => request.to_request(),
+            }
+        };
+        let resp = test::call_service(&mut app, get("/api/v2/messages", None)).await;
+        let etag = String::from(resp.headers().get("etag").unwrap().to_str().unwrap());
+        let last_modified = String::from(resp.headers().get("last-modified").unwrap().to_str().unwrap());
+        let resp = test::call_service(&mut app, get("/api/v2/messages", Some(("If-None-Match", &etag)))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get("etag").unwrap(), etag.as_str());
+        assert!(test::read_body(resp).await.is_empty());
+        let resp = test::call_service(&mut app, get("/api/v2/messages", Some(("If-Modified-Since", &last_modified)))).await;
+        assert_eq!(resp.status(), StatusCode::OK); //同一秒内可能还有修改
+        let since: HttpDate = last_modified.parse().unwrap();
+        let next_second = HttpDate::from(std::time::SystemTime::from(since) + Duration::from_secs(1)).to_string();
+        let resp = test::call_service(&mut app, get("/api/v2/messages", Some(("If-Modified-Since", &next_second)))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        for uri in ["/api/v1/message", "/api/v2/messages?tz=Asia/Shanghai"] {
+            let resp = test::call_service(&mut app, get(uri, Some(("If-None-Match", &etag)))).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{} should not share the ETag", uri); //接口版本或时区不同，ETag也不同
+        }
+        let req = test::TestRequest::post()
+            .uri("/api/v2/messages")
+            .set_json(&ReceiveMessageJson { title: String::from("new"), content: String::from("message") })
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let resp = test::call_service(&mut app, get("/api/v2/messages", Some(("If-None-Match", &etag)))).await;
+        assert_eq!(resp.status(), StatusCode::OK); //有新留言后缓存失效
+        let changed = String::from(resp.headers().get("etag").unwrap().to_str().unwrap());
+        assert_ne!(changed, etag);
+        test::call_service(&mut app, test::TestRequest::delete().uri("/api/v2/messages").to_request()).await;
+        let resp = test::call_service(&mut app, get("/api/v2/messages", Some(("If-None-Match", &changed)))).await;
+        assert_eq!(resp.status(), StatusCode::OK); //删除留言也会让缓存失效
+
+        let version = operations::ListingVersion { count: 2, max_id: Some(5), revision: 7, updated_at: Utc::now().naive_utc() };
+        let etag = version.etag("v2-json", &crate::timezone::Zone::Utc);
+        assert_eq!(etag.to_string(), "\"7-2-5-83cbaa7bbe52e014\""); //重启或升级之后同样的内容还是同一个ETag
+    }
+
+    #[actix_rt::test]
+    async fn test_feeds() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        {
+            let db_connection = database.get().unwrap();
+            let alice = crate::board::insert_user(&db_connection, "Alice").unwrap();
+            let bob = crate::board::insert_user(&db_connection, "Bob").unwrap();
+            for index in 0..crate::feed::FEED_PAGE_SIZE {
+                crate::board::insert_message(&db_connection, alice.id, &format!("Alice #{}", index), "hi").unwrap();
+            }
+            crate::board::insert_message(&db_connection, bob.id, "<b>Tom & Jerry</b>", "a\u{1}b < c").unwrap();
+        }
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), Default::default()))
+        ).await;
+        let fetch = |uri: &str| test::TestRequest::get().uri(uri).header("host", "board.example").to_request();
+        let resp = test::call_service(&mut app, fetch("/feed.atom")).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/atom+xml; charset=utf-8");
+        let atom = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(atom.matches("<entry>").count(), crate::feed::FEED_PAGE_SIZE as usize);
+        assert!(atom.contains("<title type=\"text\">&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;</title>")); //最新的留言在最前面，内容已转义
+        assert!(atom.contains("<content type=\"text\">ab &lt; c</content>")); //去掉了控制字符
+        assert!(atom.contains("<author><name>Bob</name></author>"));
+        assert!(atom.contains("<link rel=\"next\" href=\"http://board.example/feed.atom?page=2\"/>"));
+        let resp = test::call_service(&mut app, fetch("/feed.rss?page=2")).await;
+        let rss = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(rss.matches("<item>").count(), 1);
+        assert!(rss.contains("<atom:link rel=\"previous\" href=\"http://board.example/feed.rss\"/>"));
+        assert!(!rss.contains("rel=\"next\""));
+        assert!(rss.contains("<dc:creator>Alice</dc:creator>"));
+        let resp = test::call_service(&mut app, fetch("/user/Bob/feed.rss")).await;
+        let rss = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(rss.matches("<item>").count(), 1);
+        assert!(rss.contains("<title>Messages by Bob</title>"));
+        let resp = test::call_service(&mut app, fetch("/user/Nobody/feed.atom")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        for page in [String::from("0"), i64::MAX.to_string()] {
+            let resp = test::call_service(&mut app, fetch(&format!("/feed.atom?page={}", page))).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST); //太大的页码会让偏移量溢出
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_csv_listing() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        {
+            let db_connection = database.get().unwrap();
+            let alice = crate::board::insert_user(&db_connection, "Alice").unwrap();
+            crate::board::insert_message(&db_connection, alice.id, "a, \"quoted\" title", "line 1\nline 2").unwrap();
+            crate::board::insert_message(&db_connection, alice.id, "=HYPERLINK(\"x\")", "-1").unwrap();
+            for index in 0..600 {
+                crate::board::insert_message(&db_connection, alice.id, &format!("#{}", index), "hi").unwrap();
+            }
+        }
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), Default::default()))
+        ).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v2/messages?format=csv&limit=2").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("content-type").unwrap().to_str().unwrap().starts_with("text/csv"));
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let lines: Vec<&str> = body.split("\r\n").collect();
+        assert_eq!(lines[0], "id,user,title,content,pub_date");
+        assert!(lines[1].starts_with("1,1,\"a, \"\"quoted\"\" title\",\"line 1\nline 2\","));
+        assert!(lines[2].starts_with("2,1,\"'=HYPERLINK(\"\"x\"\")\",'-1,")); //公式开头的文本加上`'`
+        assert_eq!(lines.len(), 4);
+        let req = test::TestRequest::get().uri("/api/message?limit=550&offset=30&bom=true").header("Accept", "text/csv, application/json;q=0.5").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.starts_with("\u{feff}id,"));
+        let ids: Vec<i32> = body.lines().skip(1).map(|line| line.split(',').next().unwrap().parse().unwrap()).collect();
+        assert_eq!(ids, (31..=580).collect::<Vec<_>>()); //跨过一批的边界，既不重复也不遗漏
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/v2/messages").header("Accept", "text/csv;q=0.5, application/json").to_request()).await;
+        assert!(resp.headers().get("content-type").unwrap().to_str().unwrap().starts_with("application/json"));
+        for uri in ["/api/v2/messages?format=xml", "/api/v2/messages?format=csv&bom=maybe"] {
+            let resp = test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_graphql() {
+        actix_web::rt::System::new("test_graphql").block_on(graphql_requests()); //dataloader用actix-web的`rt::spawn`，需要在它自己的System里运行
+    }
+
+    async fn graphql_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let limits = ContentLimits::default();
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(limits)
+            .configure(|routes| crate::register_routes(routes, limits, Default::default()))
+        ).await;
+        let author_batches = |metrics: &str| -> u64 {
+            metrics
+                .lines()
+                .find(|line| line.starts_with("db_query_duration_seconds_count{query=\"load_authors\"}"))
+                .map_or(0, |line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        };
+        let graphql = |query: serde_json::Value, user: &str| {
+            test::TestRequest::post()
+                .uri("/api/graphql")
+                .cookie(Cookie::new("user", String::from(user)))
+                .set_json(&query)
+                .to_request()
+        };
+        for (index, user) in ["Alice", "Bob", "Alice", "Bob", "Alice"].iter().enumerate() {
+            let query = serde_json::json!({
+                "query": "mutation($title: String!) { postMessage(title: $title, content: \"hi\") { id author { name } } }",
+                "variables": { "title": format!("#{}", index) },
+            });
+            let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, user)).await;
+            assert_eq!(resp["data"]["postMessage"]["author"]["name"], *user);
+        }
+        let metrics = |app_metrics: Bytes| author_batches(std::str::from_utf8(&app_metrics).unwrap());
+        let before = metrics(test::read_body(test::call_service(&mut app, test::TestRequest::get().uri("/metrics").to_request()).await).await);
+        let query = serde_json::json!({ "query": "{ messages(first: 4) { edges { cursor node { id title author { name } } } pageInfo { hasNextPage endCursor } } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, "Alice")).await;
+        let after = metrics(test::read_body(test::call_service(&mut app, test::TestRequest::get().uri("/metrics").to_request()).await).await);
+        assert_eq!(after - before, 1, "authors should be loaded in one batch"); //4条留言、2个作者，只查一次用户表
+        let connection = &resp["data"]["messages"];
+        let names: Vec<&str> = connection["edges"].as_array().unwrap().iter().map(|edge| edge["node"]["author"]["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["Alice", "Bob", "Alice", "Bob"]);
+        assert_eq!(connection["pageInfo"]["hasNextPage"], true);
+        let query = serde_json::json!({
+            "query": "query($after: String) { messages(first: 4, after: $after) { edges { node { title } } pageInfo { hasNextPage hasPreviousPage } } }",
+            "variables": { "after": connection["pageInfo"]["endCursor"] },
+        });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, "Alice")).await;
+        assert_eq!(resp["data"]["messages"]["edges"][0]["node"]["title"], "#4");
+        assert_eq!(resp["data"]["messages"]["pageInfo"]["hasNextPage"], false);
+        assert_eq!(resp["data"]["messages"]["pageInfo"]["hasPreviousPage"], true);
+        let query = serde_json::json!({ "query": "{ user(name: \"Bob\") { messages(last: 1) { edges { node { title } } pageInfo { hasPreviousPage } } } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, "Alice")).await;
+        assert_eq!(resp["data"]["user"]["messages"]["edges"][0]["node"]["title"], "#3");
+        assert_eq!(resp["data"]["user"]["messages"]["pageInfo"]["hasPreviousPage"], true);
+        let query = serde_json::json!({ "query": "mutation { deleteMessage(id: 1) }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query.clone(), "Alice")).await;
+        assert_eq!(resp["data"]["deleteMessage"], true);
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, "Alice")).await;
+        assert_eq!(resp["data"]["deleteMessage"], false);
+        let query = serde_json::json!({ "query": "mutation { postMessage(title: \"x\", content: \"y\") { id } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, &"x".repeat(limits.max_name_length + 1))).await;
+        assert_eq!(resp["errors"][0]["extensions"]["code"], "invalid_field"); //错误码和REST接口相同
+        assert_eq!(resp["errors"][0]["extensions"]["field"], "user");
+        let query = serde_json::json!({ "query": "{ messages(first: 100000) { edges { cursor } } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(query, "Alice")).await;
+        assert_eq!(resp["errors"][0]["extensions"]["field"], "first");
+        let req = test::TestRequest::post().uri("/api/graphql").header("Content-Type", "application/json").set_payload("{").to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::BAD_REQUEST);
+        let query = r#"{"query": "{ messages(first: 1) { edges { cursor } } }"}"#;
+        let req = test::TestRequest::post().uri("/api/graphql").header("Content-Type", "text/plain").set_payload(query).to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE); //简单请求不用预检，不能接受
+        let cross_origin = |query: serde_json::Value| {
+            test::TestRequest::post().uri("/api/graphql").header("Origin", "https://evil.example.com").set_json(&query).to_request()
+        };
+        let resp = test::call_service(&mut app, cross_origin(serde_json::json!({ "query": "mutation { deleteMessage(id: 2) }" }))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp: serde_json::Value = test::read_response_json(&mut app, graphql(serde_json::json!({ "query": "{ message(id: 2) { id } }" }), "Alice")).await;
+        assert_eq!(resp["data"]["message"]["id"], 2); //被拒绝的变更没有执行
+        let query = "mutation { ...remove } fragment remove on Mutation { deleteMessage(id: 2) }";
+        let resp = test::call_service(&mut app, cross_origin(serde_json::json!({ "query": query }))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN); //通过片段删除也一样
+        let resp = test::call_service(&mut app, cross_origin(serde_json::json!({ "query": "{ message(id: 2) { id } }" }))).await;
+        assert_eq!(resp.status(), StatusCode::OK); //跨域查询不受影响
+        let query = serde_json::json!({ "query": "mutation { postMessage(title: \"cors\", content: \"x\") { id } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut app, cross_origin(query)).await;
+        assert!(resp["data"]["postMessage"]["id"].is_number()); //发帖能不能跨域由CORS预检决定
+        let same_origin = test::TestRequest::post()
+            .uri("/api/graphql")
+            .header("Host", "board.example.com")
+            .header("Origin", "http://board.example.com")
+            .set_json(&serde_json::json!({ "query": "mutation { deleteMessage(id: 2) }" }))
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, same_origin).await;
+        assert_eq!(resp["data"]["deleteMessage"], true); //同源页面（比如GraphiQL）带着Origin也可以删除
+        let rate_limits = crate::ratelimit::RateLimits::new(&crate::config::RateLimitConfig {
+            post_message: crate::config::Quota { burst: 1, per_minute: 1 },
+            ..Default::default()
+        });
+        let mut limited_app = test::init_service(
+            App::new()
+            .app_data(web::Data::new(rate_limits))
+            .data(database.clone())
+            .data(limits)
+            .configure(|routes| crate::register_routes(routes, limits, Default::default()))
+        ).await;
+        let query = serde_json::json!({ "query": "mutation { a: postMessage(title: \"a\", content: \"x\") { id } b: postMessage(title: \"b\", content: \"x\") { id } }" });
+        let resp: serde_json::Value = test::read_response_json(&mut limited_app, graphql(query, "Alice")).await;
+        assert!(resp["data"]["a"]["id"].is_number());
+        assert_eq!(resp["errors"][0]["extensions"]["code"], "rate_limited"); //用别名发的第二条也按发帖的配额计数
+        assert_eq!(resp["errors"][0]["path"][0], "b");
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/graphql").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK); //测试是debug构建，默认打开GraphiQL
+        let mut app = test::init_service(
+            App::new()
+            .configure(|routes| crate::register_routes(routes, limits, crate::config::ApiConfig { graphiql: false, ..Default::default() }))
+        ).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/graphql").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_event_backpressure() {
+        use crate::events::{BoardEvent, EventFilter, EventHub};
+        use futures::StreamExt;
+        let hub = EventHub::default();
+        let created = |user: i32| BoardEvent::MessageCreated {
+            message: MessageJson { id: 1, user, title: String::from("t"), content: String::from("c"), pub_date: String::new() },
+        };
+        let mut slow = hub.subscribe(EventFilter::default(), 2);
+        let mut filtered = hub.subscribe(EventFilter { users: Some(vec![2]) }, 2);
+        for _ in 0..3 {
+            hub.publish(created(1));
+        }
+        futures::executor::block_on(async {
+            assert!(slow.next().await.is_some());
+            assert!(slow.next().await.is_some());
+            assert!(slow.next().await.is_none()); //第3个事件放不下，订阅被断开
+        });
+        assert!(filtered.try_recv().is_err()); //其他用户的事件不占用缓冲区，订阅仍然有效
+        hub.publish(created(2));
+        assert_eq!(futures::executor::block_on(filtered.next()).unwrap().name(), "message.created");
+    }
+
+    #[test]
+    fn test_websocket_events() {
+        actix_web::rt::System::new("test_websocket_events").block_on(websocket_events());
+    }
+
+    /// 跳过心跳，读出下一个事件
+    async fn next_event<S>(socket: &mut S) -> serde_json::Value
+    where
+        S: futures::Stream<Item = Result<actix_http::ws::Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        use actix_http::ws::Frame;
+        use futures::StreamExt;
+        loop {
+            match socket.next().await.unwrap().unwrap() {
+                Frame::Text(text) => return serde_json::from_slice(&text).unwrap(),
+                Frame::Ping(_) => continue,
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+    }
+
+    async fn websocket_events() {
+        use actix_http::ws::{CloseCode, Frame, Message};
+        use futures::{SinkExt, StreamExt};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        {
+            let db_connection = database.get().unwrap();
+            crate::board::insert_user(&db_connection, "Carol").unwrap();
+            crate::board::insert_user(&db_connection, "Dave").unwrap();
+        }
+        let api = crate::config::ApiConfig { heartbeat_secs: 1, client_timeout_secs: 2, ..Default::default() };
+        let server_database = database.clone();
+        let mut server = test::start(move || {
+            App::new()
+            .data(server_database.clone())
+            .data(ContentLimits::default())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), api))
+        });
+        let response = server.get("/api/ws?user=Nobody").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let mut socket = server.ws_at("/api/ws?user=Carol").await.unwrap();
+        for (user, title) in [("Dave", "from dave"), ("Carol", "from carol")] {
+            let response = server
+                .post("/api/v2/messages")
+                .cookie(Cookie::new("user", user))
+                .send_json(&ReceiveMessageJson { title: String::from(title), content: String::from("hi") })
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let event = next_event(&mut socket).await;
+        assert_eq!(event["type"], "message.created");
+        assert_eq!(event["message"]["title"], "from carol"); //Dave的留言被过滤掉了，先到的就是Carol的
+        let response = server.delete("/api/v2/messages").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        loop {
+            let event = next_event(&mut socket).await;
+            if event["type"] == "board.cleared" {
+                break;
+            }
+            assert_ne!(event["message"]["title"], "from dave");
+        } //其他测试也在发布事件，跳过无关的
+        socket.send(Message::Ping(Bytes::from_static(b"hi"))).await.unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        let (mut pinged, mut ponged) = (false, false);
+        let closed = actix_web::rt::time::timeout(timeout, async {
+            while let Some(frame) = socket.next().await {
+                match frame.unwrap() {
+                    Frame::Pong(data) => ponged = data == Bytes::from_static(b"hi"),
+                    Frame::Ping(_) => pinged = true,
+                    Frame::Close(reason) => return reason,
+                    _ => {},
+                }
+            }
+            None
+        }).await.unwrap();
+        assert!(ponged && pinged);
+        assert_eq!(closed.unwrap().code, CloseCode::Away); //没有回应心跳，超时后被断开
+    }
+
+    #[test]
+    fn test_event_stream() {
+        actix_web::rt::System::new("test_event_stream").block_on(event_stream());
+    }
+
+    /// 读出下一条SSE消息，返回`id`、`event`和`data`，跳过注释
+    async fn next_sse(body: &mut ResponseBody<Body>, buffer: &mut String) -> (Option<String>, String, serde_json::Value) {
+        use futures::StreamExt;
+        loop {
+            if let Some(end) = buffer.find("\n\n") {
+                let block: String = buffer.drain(..end + 2).collect();
+                let field = |name: &str| block.lines().find_map(|line| line.strip_prefix(name)).map(String::from);
+                if let Some(event) = field("event: ") {
+                    return (field("id: "), event, serde_json::from_str(&field("data: ").unwrap()).unwrap());
+                }
+                continue;
+            }
+            let chunk = body.next().await.unwrap().unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    async fn event_stream() {
+        use futures::StreamExt;
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        {
+            let db_connection = database.get().unwrap();
+            let erin = crate::board::insert_user(&db_connection, "Erin").unwrap();
+            for index in 1..=3 {
+                crate::board::insert_message(&db_connection, erin.id, &format!("sse #{}", index), "hi").unwrap();
+            }
+        }
+        let api = crate::config::ApiConfig { heartbeat_secs: 1, client_timeout_secs: 2, ..Default::default() };
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), api))
+        ).await;
+        let req = test::TestRequest::get().uri("/api/message/stream").header("Last-Event-ID", "abc").to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::get().uri("/api/message/stream").header("Last-Event-ID", "1").to_request();
+        let mut resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/event-stream");
+        let mut body = resp.take_body();
+        let mut buffer = String::new();
+        for (id, title) in [("2", "sse #2"), ("3", "sse #3")] {
+            let (event_id, event, data) = next_sse(&mut body, &mut buffer).await;
+            assert_eq!((event_id.as_deref(), event.as_str()), (Some(id), "message-created")); //先补发断线期间的留言
+            assert_eq!(data["title"], title);
+        }
+        let post = |title: &str| {
+            test::TestRequest::post()
+                .uri("/api/v2/messages")
+                .cookie(Cookie::new("user", "Erin"))
+                .set_json(&ReceiveMessageJson { title: String::from(title), content: String::from("hi") })
+                .to_request()
+        };
+        assert_eq!(test::call_service(&mut app, post("sse live")).await.status(), StatusCode::CREATED);
+        operations::delete_message(&database, 4).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let (event_id, event, data) = next_sse(&mut body, &mut buffer).await;
+            if data["title"] == "sse live" {
+                received.push((event_id, event));
+            } //其他测试也在发布事件，跳过无关的
+        }
+        assert_eq!(received, [(Some(String::from("4")), String::from("message-created")), (None, String::from("message-deleted"))]);
+        operations::delete_message(&database, 3).unwrap();
+        test::call_service(&mut app, post("sse reused")).await; //删掉最大的id后新留言会重用它
+        operations::delete_messages(&database).unwrap();
+        test::call_service(&mut app, post("sse after clear")).await; //清空后id从1重新开始
+        let mut received = Vec::new();
+        actix_web::rt::time::timeout(std::time::Duration::from_secs(10), async {
+            while received.len() < 2 {
+                let (event_id, event, data) = next_sse(&mut body, &mut buffer).await;
+                if event == "message-created" && data["title"].as_str().is_some_and(|title| title.starts_with("sse ")) {
+                    received.push((event_id.unwrap(), data["title"].as_str().unwrap().to_string()));
+                }
+            }
+        })
+        .await
+        .expect("events for reused ids were dropped");
+        assert_eq!(received, [(String::from("3"), String::from("sse reused")), (String::from("1"), String::from("sse after clear"))]); //和重放过的id相同也要推送
+        while !buffer.contains(": heartbeat") {
+            let chunk = body.next().await.unwrap().unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        buffer.clear();
+
+        test::call_service(&mut app, post("sse older")).await;
+        test::call_service(&mut app, post("sse newer")).await;
+        operations::delete_message(&database, 2).unwrap(); //删除较早的留言
+        let mut last_event_id = None;
+        loop {
+            let (event_id, event, data) = next_sse(&mut body, &mut buffer).await;
+            if !data["title"].as_str().is_some_and(|title| title.starts_with("sse ")) {
+                continue;
+            }
+            if event_id.is_some() {
+                last_event_id = event_id;
+            } //和浏览器一样，只有带id的事件才更新`Last-Event-ID`
+            if event == "message-deleted" {
+                break;
+            }
+        }
+        assert_eq!(last_event_id.as_deref(), Some("3"));
+        let req = test::TestRequest::get().uri("/api/message/stream").header("Last-Event-ID", last_event_id.unwrap()).to_request();
+        let mut body = test::call_service(&mut app, req).await.take_body();
+        let mut buffer = String::new();
+        test::call_service(&mut app, post("sse reconnected")).await;
+        loop {
+            let (_, event, data) = next_sse(&mut body, &mut buffer).await;
+            if event == "message-created" && data["title"].as_str().is_some_and(|title| title.starts_with("sse ")) {
+                assert_eq!(data["title"], "sse reconnected"); //重连后不会重复补发已经收到的留言
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_webhooks() {
+        actix_web::rt::System::new("test_webhooks").block_on(webhooks()); //投递用actix-web自己的awc客户端
+    }
+
+    async fn webhooks() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use actix_web::{HttpRequest, HttpResponse, http::HeaderMap};
+        use crate::config::WebhookOptions;
+        use crate::webhooks::{deliver_due, retry_delay, sign};
+        let directory = tempfile::tempdir().unwrap();
+        let database = init_isolated(directory.path());
+        let received: Arc<Mutex<Vec<(HeaderMap, Bytes)>>> = Arc::default();
+        let recorder = received.clone();
+        let receiver = test::start(move || {
+            let recorder = recorder.clone();
+            App::new().route("/hook", web::post().to(move |request: HttpRequest, body: Bytes| {
+                let mut received = recorder.lock().unwrap();
+                received.push((request.headers().clone(), body));
+                let response = match received.len() {
+                    1 => HttpResponse::InternalServerError().finish(), //第一次失败，之后成功
+                    _ => HttpResponse::Ok().finish(),
+                };
+                futures::future::ready(response)
+            }))
+        }); //代替聊天工具接收投递
+        let mut app = test::init_service(
+            App::new()
+            .data(database.clone())
+            .data(ContentLimits::default())
+            .configure(|routes| crate::register_routes(routes, ContentLimits::default(), Default::default()))
+        ).await;
+        for body in [
+            serde_json::json!({ "url": "ftp://example.com/hook", "events": ["message.created"] }),
+            serde_json::json!({ "url": receiver.url("/hook"), "events": ["message.edited"] }),
+            serde_json::json!({ "url": receiver.url("/hook"), "events": [] }),
+        ] {
+            let req = test::TestRequest::post().uri("/api/admin/webhooks").set_json(&body).to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::BAD_REQUEST);
+        }
+        let req = test::TestRequest::post()
+            .uri("/api/admin/webhooks")
+            .set_json(&serde_json::json!({ "url": receiver.url("/hook"), "secret": "s3cret", "events": ["message.created", "message.created"] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(created["events"], serde_json::json!(["message.created"]));
+        assert_eq!(created["secret"], "s3cret");
+        let hook = created["id"].as_i64().unwrap();
+        let req = test::TestRequest::post()
+            .uri("/api/v2/messages")
+            .cookie(Cookie::new("user", "Frank"))
+            .set_json(&ReceiveMessageJson { title: String::from("to chat"), content: String::from("hi") })
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::delete().uri("/api/v2/messages").to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK); //没有订阅user.created和board.cleared
+        let options = WebhookOptions {
+            poll_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            max_attempts: 3,
+            retry_base: Duration::from_secs(1),
+            retry_max: Duration::from_secs(1),
+        };
+        let deliveries = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+        let log_uri = format!("/api/admin/webhooks/{}/deliveries", hook);
+        assert_eq!(deliver_due(&database, &options).await, Ok(1));
+        let log: serde_json::Value = test::read_body_json(test::call_service(&mut app, deliveries(log_uri.clone())).await).await;
+        assert_eq!(log.as_array().unwrap().len(), 1);
+        assert_eq!((&log[0]["status"], &log[0]["attempts"], &log[0]["last_status"]), (&serde_json::json!("pending"), &serde_json::json!(1), &serde_json::json!(500)));
+        assert_eq!(deliver_due(&database, &options).await, Ok(0)); //还没到重试的时间
+        actix_web::rt::time::delay_for(Duration::from_millis(1100)).await;
+        assert_eq!(deliver_due(&database, &options).await, Ok(1));
+        let log: serde_json::Value = test::read_body_json(test::call_service(&mut app, deliveries(log_uri.clone())).await).await;
+        assert_eq!((&log[0]["status"], &log[0]["attempts"], &log[0]["last_status"]), (&serde_json::json!("delivered"), &serde_json::json!(2), &serde_json::json!(200)));
+        assert_eq!(log[0]["payload"]["message"]["title"], "to chat");
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 2);
+            let (headers, body) = &received[1];
+            let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+            assert_eq!(header("X-Webhook-Event"), "message.created");
+            assert_eq!(header("X-Webhook-Delivery"), log[0]["id"].to_string());
+            let timestamp: i64 = header("X-Webhook-Timestamp").parse().unwrap();
+            let body = std::str::from_utf8(body).unwrap();
+            assert_eq!(header("X-Webhook-Signature"), format!("sha256={}", sign("s3cret", timestamp, body)));
+            let event: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(event["type"], "message.created");
+        }
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/admin/webhooks").to_request()).await;
+        let listed: serde_json::Value = test::read_body_json(resp).await;
+        assert!(listed[0].get("secret").is_none()); //列表里不返回secret
+        let options = WebhookOptions { retry_base: Duration::from_secs(10), retry_max: Duration::from_secs(60), ..options };
+        let delays: Vec<u64> = [1, 2, 3, 4, 40].iter().map(|attempts| retry_delay(&options, *attempts).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 60, 60]);
+        let delete = || test::TestRequest::delete().uri(&format!("/api/admin/webhooks/{}", hook)).to_request();
+        assert_eq!(test::call_service(&mut app, delete()).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(test::call_service(&mut app, delete()).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test::call_service(&mut app, deliveries(log_uri)).await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc, sync::Arc, time::Duration};
+use actix_web::{Error, HttpRequest, HttpResponse, http::header, web::{self, Bytes}};
+use diesel::{RunQueryDsl, prelude::*};
+use futures::{StreamExt, future, stream};
+use serde_json::json;
+use crate::Pool;
+use crate::config::ApiConfig;
+use crate::error::ApiError;
+use crate::events::{self, BoardEvent, EventFilter, HUB};
+use crate::metrics;
+use crate::models::{MessageJson, PostMessage};
+
+/// 重放时每次从数据库读取的条数
+const REPLAY_BATCH: i64 = 500;
+
+enum Live {
+    Event(Arc<BoardEvent>),
+    Lagged,
+    Heartbeat,
+}
+
+/// 一条SSE消息。没有`id`时客户端记录的Last-Event-ID保持不变
+fn frame(id: Option<i32>, event: &str, data: &str) -> String {
+    match id {
+        Some(id) => format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data),
+        None => format!("event: {}\ndata: {}\n\n", event, data),
+    }
+}
+
+/// 新留言以留言id作为事件id。删除和修改不带事件id：删除的可能是较早的留言，
+/// 带上它的id会让浏览器的`Last-Event-ID`倒退，重连时重复补发。新用户不单独推送
+fn encode(event: &BoardEvent) -> Option<String> {
+    let (id, name, data) = match event {
+        BoardEvent::MessageCreated { message } => (Some(message.id), "message-created", serde_json::to_string(message).ok()?),
+        BoardEvent::MessageDeleted { message } => (None, "message-deleted", serde_json::to_string(message).ok()?),
+        BoardEvent::MessageUpdated { message } => (None, "message-updated", serde_json::to_string(message).ok()?),
+        BoardEvent::BoardCleared { deleted } => (None, "board-cleared", json!({ "deleted": deleted }).to_string()),
+        BoardEvent::UserCreated { .. } => return None,
+    };
+    Some(frame(id, name, &data))
+}
+
+fn last_event_id(request: &HttpRequest) -> Result<Option<i32>, ApiError> {
+    match request.headers().get("Last-Event-ID") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|text| text.trim().parse::<i32>().ok())
+            .map(Some)
+            .ok_or_else(|| ApiError::invalid_field("Last-Event-ID", "Last-Event-ID must be a message id")),
+        None => Ok(None),
+    }
+}
+
+/// 按id顺序分批读出`after`之后的留言，作为`message-created`发送。`replayed`记录已经发送的id
+fn replay(pool: Pool, after: i32, replayed: Rc<RefCell<HashSet<i32>>>) -> impl futures::Stream<Item = Result<Bytes, Error>> {
+    stream::unfold(Some(after), move |after| {
+        let pool = pool.clone();
+        let replayed = replayed.clone();
+        async move {
+            let after = after?;
+            let page = web::block(move || -> Result<Vec<PostMessage>, ApiError> {
+                use crate::schema::message::dsl::*;
+                let db_connection = pool.get()?;
+                let page = metrics::time_query("replay_messages", || {
+                    message.filter(id.gt(after)).order(id).limit(REPLAY_BATCH).load::<PostMessage>(&db_connection)
+                })?;
+                Ok(page)
+            }).await;
+            match page {
+                Ok(page) if page.is_empty() => None,
+                Ok(page) => {
+                    let next = match page.last() {
+                        Some(last) if page.len() as i64 == REPLAY_BATCH => Some(last.id),
+                        _ => None,
+                    };
+                    replayed.borrow_mut().extend(page.iter().map(|item| item.id));
+                    let body: String = page
+                        .into_iter()
+                        .map(|item| encode(&BoardEvent::MessageCreated { message: MessageJson::from(item) }).unwrap_or_default())
+                        .collect();
+                    Some((Ok(Bytes::from(body)), next))
+                },
+                Err(e) => {
+                    tracing::error!(error = %e, "event replay failed");
+                    Some((Err(Error::from(e)), None))
+                },
+            }
+        }
+    })
+}
+
+/// `text/event-stream`：推送留言的新增、删除和清空。带着`Last-Event-ID`重连时，
+/// 先从数据库补发这个id之后的留言，再转为实时推送
+pub async fn stream(request: HttpRequest, pool: web::Data<Pool>, api: web::Data<ApiConfig>) -> Result<HttpResponse, ApiError> {
+    let after = last_event_id(&request)?;
+    let subscription = HUB.subscribe(EventFilter::default(), api.event_buffer); //先订阅再重放，重放期间的新留言不会漏掉
+    let replayed = Rc::new(RefCell::new(HashSet::new())); //只跳过真正重放过的id，删除或清空后id可能被重用
+    let history = match after {
+        Some(after) => replay(pool.get_ref().clone(), after, replayed.clone()).left_stream(),
+        None => stream::empty().right_stream(),
+    };
+    let live = stream::select(
+        subscription.map(Live::Event).chain(stream::once(async { Live::Lagged })),
+        events::heartbeat(Duration::from_secs(api.heartbeat_secs)).map(|_| Live::Heartbeat),
+    )
+    .take_while(|item| future::ready(!matches!(item, Live::Lagged))) //积压太多时结束响应，客户端重连后会补发
+    .filter_map(move |item| {
+        let text = match item {
+            Live::Event(event) => {
+                let mut replayed = replayed.borrow_mut();
+                match &*event {
+                    BoardEvent::MessageCreated { message } if replayed.remove(&message.id) => None, //重放时已经发过，只跳过一次
+                    BoardEvent::MessageDeleted { message } => {
+                        replayed.remove(&message.id);
+                        encode(&event)
+                    },
+                    BoardEvent::BoardCleared { .. } => {
+                        replayed.clear();
+                        encode(&event)
+                    },
+                    event => encode(event),
+                }
+            },
+            _ => Some(String::from(": heartbeat\n\n")),
+        };
+        future::ready(text.map(|text| Ok(Bytes::from(text))))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("X-Accel-Buffering", "no") //让nginx不要缓冲
+        .streaming(Box::pin(history.chain(live))))
+}
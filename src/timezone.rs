@@ -0,0 +1,147 @@
+use std::fmt;
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{RunQueryDsl, insert_into, prelude::*};
+use crate::models::{PostMessage, PostUser};
+use crate::schema::timestamp_conversion;
+
+/// 时区：用于展示时间，或者在转换旧数据时说明旧数据是按哪个时区记录的
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    /// 接受`UTC`、`local`、`+08:00`这样的偏移量，或者`Asia/Shanghai`这样的IANA时区名
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.eq_ignore_ascii_case("utc") || text == "Z" {
+            return Ok(Zone::Utc);
+        }
+        if text.eq_ignore_ascii_case("local") {
+            return Ok(Zone::Local);
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(&format!("2000-01-01T00:00:00{}", text)) {
+            return Ok(Zone::Fixed(*time.offset()));
+        }
+        text.parse::<Tz>().map(Zone::Named).map_err(|_| format!("{} is not a valid timezone", text))
+    }
+
+    /// 时区的规范名称，比如`UTC`、`+08:00`或`Asia/Shanghai`
+    pub fn name(&self) -> String {
+        match self {
+            Zone::Utc => String::from("UTC"),
+            Zone::Local => String::from("local"),
+            Zone::Fixed(offset) => offset.to_string(),
+            Zone::Named(tz) => String::from(tz.name()),
+        }
+    }
+
+    /// 把数据库中的UTC时间格式化为这个时区下的RFC 3339字符串
+    pub fn format(&self, utc: &NaiveDateTime) -> String {
+        let time = DateTime::<Utc>::from_utc(*utc, Utc);
+        match self {
+            Zone::Utc => time.to_rfc3339(),
+            Zone::Local => time.with_timezone(&Local).to_rfc3339(),
+            Zone::Fixed(offset) => time.with_timezone(offset).to_rfc3339(),
+            Zone::Named(tz) => time.with_timezone(tz).to_rfc3339(),
+        }
+    }
+
+    /// 把这个时区下的本地时间转换为UTC时间。夏令时回拨造成的重复时间取较早的一个，
+    /// 夏令时跳过的不存在的时间返回`None`
+    pub fn local_to_utc(&self, local: &NaiveDateTime) -> Option<NaiveDateTime> {
+        fn earliest<T: TimeZone>(result: LocalResult<DateTime<T>>) -> Option<NaiveDateTime> {
+            result.earliest().map(|time| time.naive_utc())
+        }
+        match self {
+            Zone::Utc => Some(*local),
+            Zone::Local => earliest(Local.from_local_datetime(local)),
+            Zone::Fixed(offset) => earliest(offset.from_local_datetime(local)),
+            Zone::Named(tz) => earliest(tz.from_local_datetime(local)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConversionError {
+    AlreadyConverted,
+    Nonexistent(String),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::AlreadyConverted => write!(f, "timestamps have already been converted to UTC"),
+            ConversionError::Nonexistent(what) => write!(f, "{}", what),
+            ConversionError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<diesel::result::Error> for ConversionError {
+    fn from(e: diesel::result::Error) -> Self {
+        ConversionError::Database(e)
+    }
+}
+
+/// 旧版本按服务器本地时间保存时间，检查这些数据是否已经转换为UTC
+pub fn conversion_pending(db_connection: &SqliteConnection) -> QueryResult<bool> {
+    use crate::schema::timestamp_conversion::dsl::*;
+    timestamp_conversion
+        .select(id)
+        .first::<i32>(db_connection)
+        .optional()
+        .map(|record| record.is_none())
+}
+
+/// 把所有用户和留言的时间从`source`时区转换为UTC，返回转换的行数。
+/// 整个过程在一个事务里完成，而且只能执行一次。
+pub fn convert_to_utc(db_connection: &SqliteConnection, source: &Zone, source_name: &str) -> Result<usize, ConversionError> {
+    db_connection.transaction::<_, ConversionError, _>(|| {
+        if !conversion_pending(db_connection)? {
+            return Err(ConversionError::AlreadyConverted);
+        }
+        let mut converted = 0;
+        {
+            use crate::schema::user::dsl::*;
+            for item in user.load::<PostUser>(db_connection)? {
+                let utc = source
+                    .local_to_utc(&item.register_date)
+                    .ok_or_else(|| ConversionError::Nonexistent(
+                        format!("register_date of user {} does not exist in {}", item.id, source_name)
+                    ))?;
+                diesel::update(user.find(item.id))
+                    .set(register_date.eq(utc))
+                    .execute(db_connection)?;
+                converted += 1;
+            }
+        }
+        {
+            use crate::schema::message::dsl::*;
+            for item in message.load::<PostMessage>(db_connection)? {
+                let utc = source
+                    .local_to_utc(&item.pub_date)
+                    .ok_or_else(|| ConversionError::Nonexistent(
+                        format!("pub_date of message {} does not exist in {}", item.id, source_name)
+                    ))?;
+                diesel::update(message.find(item.id))
+                    .set(pub_date.eq(utc))
+                    .execute(db_connection)?;
+                converted += 1;
+            }
+        }
+        insert_into(timestamp_conversion::table)
+            .values((
+                timestamp_conversion::source_timezone.eq(source_name),
+                timestamp_conversion::converted_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(db_connection)?;
+        Ok(converted)
+    })
+}
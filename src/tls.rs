@@ -0,0 +1,108 @@
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+use actix_web::{HttpRequest, HttpResponse, http::header, rt, web};
+use rustls::{
+    ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig,
+    internal::pemfile, sign::{self, CertifiedKey},
+};
+
+/// 从PEM文件中读取证书链和私钥，私钥可以是PKCS#8或RSA格式
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+    };
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|_| format!("{} is not a PEM certificate file", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("{} contains no certificates", cert_path.display()));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("{} contains no PKCS#8 or RSA private key", key_path.display()))?;
+    let signing_key = sign::any_supported_type(key)
+        .map_err(|_| format!("{} contains an unsupported private key", key_path.display()))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+/// 可以在运行中替换的证书。每次握手都读取当前的证书，重新加载失败时继续使用旧的
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<CertifiedKey>,
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<Self>, String> {
+        Ok(Arc::new(ReloadableCert {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(load_certified_key(cert_path, key_path)?),
+        }))
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        let reloaded = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = reloaded;
+        Ok(())
+    }
+
+    pub fn current(&self) -> CertifiedKey {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current())
+    }
+}
+
+pub fn server_config(certificates: Arc<ReloadableCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = certificates;
+    config
+}
+
+/// 收到SIGHUP时重新读取证书和私钥
+pub fn spawn_reload_on_sighup(certificates: Arc<ReloadableCert>) {
+    use rt::signal::unix::{SignalKind, signal};
+    rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                tracing::error!(error = %e, "unable to listen for SIGHUP; certificates will not be reloaded");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match certificates.reload() {
+                Ok(()) => tracing::info!(cert = %certificates.cert_path.display(), "TLS certificate reloaded"),
+                Err(e) => tracing::error!(error = %e, "TLS certificate reload failed; keeping the previous certificate"),
+            }
+        }
+    });
+}
+
+/// HTTPS监听的端口，重定向时用
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsPort(pub u16);
+
+/// 把HTTP请求永久重定向到同一主机的HTTPS地址
+pub async fn redirect_to_https(request: HttpRequest, port: web::Data<HttpsPort>) -> HttpResponse {
+    let connection_info = request.connection_info();
+    let authority = connection_info.host();
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => &authority[..rest.find(']').map_or(authority.len(), |end| end + 2)],
+        None => authority.split(':').next().unwrap_or(authority),
+    }; //去掉HTTP的端口，IPv6地址保留方括号
+    let location = match port.0 {
+        443 => format!("https://{}{}", host, request.uri()),
+        port => format!("https://{}:{}{}", host, port, request.uri()),
+    };
+    HttpResponse::PermanentRedirect().header(header::LOCATION, location).finish()
+}
@@ -0,0 +1,243 @@
+use std::{collections::HashMap, io::BufRead};
+use diesel::{RunQueryDsl, insert_into, prelude::*, result::Error as DieselError};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+use crate::config::ContentLimits;
+use crate::events::{self, BoardEvent};
+use crate::models::*;
+
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+/// JSONL中的一行，要么是用户，要么是留言
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BoardRecord {
+    User(UserJson),
+    Message(MessageJson),
+}
+
+impl BoardRecord {
+    /// 根据字段判断一行是用户还是留言，这样出错时能给出具体是哪个字段有问题
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(line).map_err(|e| format!("invalid JSON: {}", e))?;
+        if value.get("pub_date").is_some() {
+            serde_json::from_value(value).map(BoardRecord::Message).map_err(|e| format!("invalid message: {}", e))
+        } else if value.get("register_date").is_some() {
+            serde_json::from_value(value).map(BoardRecord::User).map_err(|e| format!("invalid user: {}", e))
+        } else {
+            Err(String::from("neither a user nor a message"))
+        }
+    }
+}
+
+/// 导出进行到哪里了：先按id导出所有用户，再按id导出所有留言
+#[derive(Debug, Clone, Copy)]
+pub enum ExportCursor {
+    Users(i32),
+    Messages(i32),
+    Done,
+}
+
+impl ExportCursor {
+    pub fn start() -> Self {
+        ExportCursor::Users(0)
+    }
+}
+
+/// 从`cursor`开始导出一页，返回这一页的JSONL文本和下一页的位置。
+/// 只有在全部导出完毕时才会返回空字符串。
+pub fn export_chunk(db_connection: &SqliteConnection, mut cursor: ExportCursor) -> QueryResult<(String, ExportCursor)> {
+    loop {
+        let (records, next) = match cursor {
+            ExportCursor::Users(last_id) => {
+                use crate::schema::user::dsl::*;
+                let page = user
+                    .filter(id.gt(last_id))
+                    .order(id)
+                    .limit(EXPORT_PAGE_SIZE)
+                    .load::<PostUser>(db_connection)?;
+                let next = match page.last() {
+                    Some(last) => ExportCursor::Users(last.id),
+                    None => ExportCursor::Messages(0),
+                };
+                (page.into_iter().map(|x| BoardRecord::User(x.into())).collect::<Vec<_>>(), next)
+            },
+            ExportCursor::Messages(last_id) => {
+                use crate::schema::message::dsl::*;
+                let page = message
+                    .filter(id.gt(last_id))
+                    .order(id)
+                    .limit(EXPORT_PAGE_SIZE)
+                    .load::<PostMessage>(db_connection)?;
+                let next = match page.last() {
+                    Some(last) => ExportCursor::Messages(last.id),
+                    None => ExportCursor::Done,
+                };
+                (page.into_iter().map(|x| BoardRecord::Message(x.into())).collect::<Vec<_>>(), next)
+            },
+            ExportCursor::Done => return Ok((String::new(), ExportCursor::Done)),
+        };
+        cursor = next;
+        if !records.is_empty() {
+            let mut chunk = String::new();
+            for record in records {
+                chunk.push_str(&serde_json::to_string(&record).expect("records are always serializable"));
+                chunk.push('\n');
+            }
+            return Ok((chunk, cursor));
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LineError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub users_created: usize,
+    pub users_matched: usize,
+    pub messages_created: usize,
+    pub errors: Vec<LineError>,
+}
+
+/// 逐行导入JSONL。用户按名字匹配已有用户，留言的作者据此重新映射；
+/// 有问题的行会记录在报告里并跳过。`dry_run`时所有改动都会回滚。
+/// 新建的用户和留言和其他接口一样产生`UserCreated`、`MessageCreated`事件
+pub fn import_board<R: BufRead>(
+    db_connection: &SqliteConnection,
+    input: R,
+    dry_run: bool,
+    limits: &ContentLimits,
+) -> QueryResult<ImportReport> {
+    let mut report = ImportReport { dry_run, ..Default::default() };
+    let mut pending = Vec::new();
+    let result = db_connection.immediate_transaction::<_, DieselError, _>(|| {
+        let mut authors: HashMap<i32, i32> = HashMap::new(); //文件中的用户id -> 数据库中的用户id
+        for (index, line) in input.lines().enumerate() {
+            let outcome = line
+                .map_err(|e| format!("unreadable line: {}", e))
+                .and_then(|line| {
+                    if line.trim().is_empty() {
+                        return Ok(None);
+                    }
+                    match BoardRecord::parse(&line)? {
+                        BoardRecord::User(item) => import_user(db_connection, item, limits, &mut authors, &mut report),
+                        BoardRecord::Message(item) => import_message(db_connection, item, limits, &authors, &mut report),
+                    }
+                });
+            match outcome {
+                Ok(Some(event)) => pending.push(event),
+                Ok(None) => {},
+                Err(error) => report.errors.push(LineError { line: index + 1, error }),
+            }
+        }
+        if dry_run {
+            Err(DieselError::RollbackTransaction)
+        } else {
+            events::record_all(db_connection, &pending)
+        }
+    });
+    match result {
+        Ok(()) => {
+            pending.into_iter().for_each(events::publish);
+            Ok(report)
+        },
+        Err(DieselError::RollbackTransaction) => Ok(report),
+        Err(e) => Err(e),
+    }
+}
+
+fn import_user(
+    db_connection: &SqliteConnection,
+    item: UserJson,
+    limits: &ContentLimits,
+    authors: &mut HashMap<i32, i32>,
+    report: &mut ImportReport,
+) -> Result<Option<BoardEvent>, String> {
+    use crate::schema::user::dsl::*;
+    if item.name.len() > limits.max_name_length {
+        return Err(format!("user name '{}' too long", item.name));
+    }
+    let date = parse_timestamp(&item.register_date).map_err(|e| format!("invalid register_date: {}", e))?;
+    if let Some(existing) = user
+        .filter(name.eq(&item.name))
+        .first::<PostUser>(db_connection)
+        .optional()
+        .map_err(|e| e.to_string())? {
+            authors.insert(item.id, existing.id);
+            report.users_matched += 1;
+            return Ok(None);
+        }
+    let id_taken = user
+        .find(item.id)
+        .first::<PostUser>(db_connection)
+        .optional()
+        .map_err(|e| e.to_string())?
+        .is_some();
+    let new_user_id = if id_taken {
+        match user.order_by(id.desc()).first::<PostUser>(db_connection) {
+            Ok(last) => last.id + 1,
+            Err(_) => 1,
+        }
+    } else {
+        item.id
+    }; //尽量保留原来的id，被占用时才分配新的
+    let new_user = PostUser {
+        id: new_user_id,
+        name: item.name,
+        register_date: date,
+    };
+    insert_into(user)
+        .values(&new_user)
+        .execute(db_connection)
+        .map_err(|e| e.to_string())?;
+    authors.insert(item.id, new_user_id);
+    report.users_created += 1;
+    Ok(Some(BoardEvent::UserCreated { user: UserJson::from(new_user) }))
+}
+
+fn import_message(
+    db_connection: &SqliteConnection,
+    item: MessageJson,
+    limits: &ContentLimits,
+    authors: &HashMap<i32, i32>,
+    report: &mut ImportReport,
+) -> Result<Option<BoardEvent>, String> {
+    use crate::schema::message::dsl::*;
+    let author = *authors
+        .get(&item.user)
+        .ok_or_else(|| format!("unknown author {} (users must come before their messages)", item.user))?;
+    if item.title.len() > limits.max_title_length {
+        return Err(String::from("field 'title' too long"));
+    }
+    if item.content.len() > limits.max_content_length {
+        return Err(String::from("field 'content' too long"));
+    }
+    let date = parse_timestamp(&item.pub_date).map_err(|e| format!("invalid pub_date: {}", e))?;
+    if message
+        .find(item.id)
+        .first::<PostMessage>(db_connection)
+        .optional()
+        .map_err(|e| e.to_string())?
+        .is_some() {
+            return Err(format!("message {} already exists", item.id));
+        }
+    let new_message = PostMessage {
+        id: item.id,
+        user: author,
+        title: item.title,
+        content: item.content,
+        pub_date: date,
+    };
+    insert_into(message)
+        .values(&new_message)
+        .execute(db_connection)
+        .map_err(|e| e.to_string())?;
+    report.messages_created += 1;
+    Ok(Some(BoardEvent::MessageCreated { message: MessageJson::from(new_message) }))
+}
@@ -0,0 +1,29 @@
+use actix_web::{Error, HttpRequest, HttpResponse, web::{self, Bytes}};
+use serde_json::json;
+use crate::Pool;
+use crate::config::ContentLimits;
+use crate::error::ApiError;
+use crate::models::MessageJson;
+use crate::operations;
+
+/// 留言列表，每一项直接是`MessageJson`对象
+pub async fn list_messages(request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    operations::conditional_listing(&request, &pool, "v2", |messages| HttpResponse::Ok().json(messages))
+}
+
+/// 发表留言，返回保存后的留言
+pub async fn create_message(
+    request_raw: Result<Bytes, Error>,
+    request: HttpRequest,
+    pool: web::Data<Pool>,
+    limits: web::Data<ContentLimits>,
+) -> Result<HttpResponse, ApiError> {
+    let saved = operations::save_message(&request, request_raw, &pool, &limits)?;
+    Ok(HttpResponse::Created().json(MessageJson::from(saved)))
+}
+
+/// 删除所有留言，代替v1中的`GET /api/clearmessage`
+pub async fn delete_messages(pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    let deleted = operations::delete_messages(&pool)?;
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted })))
+}
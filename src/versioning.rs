@@ -0,0 +1,76 @@
+use std::{pin::Pin, task::{Context, Poll}};
+use actix_web::{
+    Error, HttpResponse, ResponseError, dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{HeaderName, HeaderValue, header},
+};
+use futures::future::{Future, Ready, ok};
+use crate::error::ApiError;
+
+/// 给v1的接口加上`Deprecation`和指向v2的`Link`头。
+/// 打开`api.django_compat`时把错误响应改回原来Django版本的纯文本
+#[derive(Debug, Clone, Copy)]
+pub struct V1 {
+    pub successor: &'static str,
+    pub django_compat: bool,
+}
+
+impl<S> Transform<S> for V1
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = V1Middleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(V1Middleware { service, options: *self })
+    }
+}
+
+pub struct V1Middleware<S> {
+    service: S,
+    options: V1,
+}
+
+impl<S> Service for V1Middleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let options = self.options;
+        let future = self.service.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            if options.django_compat {
+                let text = response
+                    .response()
+                    .error()
+                    .and_then(|error| error.as_error::<ApiError>())
+                    .map(|error| (error.status_code(), error.to_string()));
+                if let Some((status, text)) = text {
+                    response = response.into_response(HttpResponse::build(status).body(text));
+                }
+            }
+            let headers = response.headers_mut();
+            headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+            if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", options.successor)) {
+                headers.insert(header::LINK, link);
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,368 @@
+use std::time::Duration;
+use actix_web::{Error, HttpRequest, HttpResponse, delete, get, http::Uri, post, rt, web::{self, Bytes}};
+use chrono::Utc;
+use diesel::{RunQueryDsl, insert_into, prelude::*};
+use futures::future;
+use qstring::QString;
+use ring::{hmac, rand::{SecureRandom, SystemRandom}};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::Pool;
+use crate::config::WebhookOptions;
+use crate::jobs::Jobs;
+use crate::error::ApiError;
+use crate::events::BoardEvent;
+use crate::models::{Webhook, WebhookDelivery};
+use crate::timezone::Zone;
+
+/// 可以订阅的事件，和WebSocket推送的`type`相同
+pub const EVENTS: [&str; 5] = ["message.created", "message.deleted", "message.updated", "board.cleared", "user.created"];
+
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+
+/// 每轮最多投递的条数，剩下的下一轮再投
+const DELIVERY_BATCH: i64 = 50;
+const DEFAULT_LOG_LIMIT: i64 = 50;
+const MAX_LOG_LIMIT: i64 = 500;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ReceiveWebhookJson {
+    pub url: String,
+    /// 用来计算签名，不填时自动生成
+    pub secret: Option<String>,
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct WebhookJson {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: String,
+    /// 只在创建时返回一次
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookJson {
+    fn from(item: Webhook) -> Self {
+        WebhookJson {
+            id: item.id,
+            url: item.url,
+            events: item.events.split(',').map(String::from).collect(),
+            created_at: Zone::Utc.format(&item.created_at),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DeliveryJson {
+    pub id: i32,
+    pub webhook: i32,
+    pub event: String,
+    /// `pending`、`delivered`或`failed`
+    pub status: String,
+    pub attempts: i32,
+    /// 下次尝试的时间，不再重试时为空
+    pub next_attempt_at: Option<String>,
+    /// 最后一次尝试时对方返回的状态码
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+impl From<WebhookDelivery> for DeliveryJson {
+    fn from(item: WebhookDelivery) -> Self {
+        let next_attempt_at = Some(Zone::Utc.format(&item.next_attempt_at)).filter(|_| item.status == PENDING);
+        DeliveryJson {
+            id: item.id,
+            webhook: item.webhook,
+            event: item.event,
+            next_attempt_at,
+            status: item.status,
+            attempts: item.attempts,
+            last_status: item.last_status,
+            last_error: item.last_error,
+            created_at: Zone::Utc.format(&item.created_at),
+            delivered_at: item.delivered_at.as_ref().map(|time| Zone::Utc.format(time)),
+            payload: serde_json::from_str(&item.payload).unwrap_or(serde_json::Value::Null),
+        }
+    }
+}
+
+/// 为订阅了这个事件的每个webhook排一次投递，返回排队的条数
+pub fn enqueue(db_connection: &SqliteConnection, board_event: &BoardEvent) -> QueryResult<usize> {
+    use crate::schema::{webhook, webhook_delivery};
+    let name = board_event.name();
+    let targets: Vec<i32> = webhook::table
+        .select((webhook::id, webhook::events))
+        .load::<(i32, String)>(db_connection)?
+        .into_iter()
+        .filter(|(_, events)| events.split(',').any(|item| item == name))
+        .map(|(target, _)| target)
+        .collect();
+    if targets.is_empty() {
+        return Ok(0);
+    }
+    let body = serde_json::to_string(board_event).map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    let now = Utc::now().naive_utc();
+    let rows: Vec<_> = targets
+        .into_iter()
+        .map(|target| (
+            webhook_delivery::webhook.eq(target),
+            webhook_delivery::event.eq(name),
+            webhook_delivery::payload.eq(&body),
+            webhook_delivery::status.eq(PENDING),
+            webhook_delivery::attempts.eq(0),
+            webhook_delivery::next_attempt_at.eq(now),
+            webhook_delivery::created_at.eq(now),
+        ))
+        .collect();
+    insert_into(webhook_delivery::table).values(&rows).execute(db_connection)
+}
+
+/// 对`"{timestamp}.{body}"`计算HMAC-SHA256，十六进制小写
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+    tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// 第`attempts`次失败后等多久再试：`retry_base`每次翻倍，不超过`retry_max`
+pub fn retry_delay(options: &WebhookOptions, attempts: i32) -> Duration {
+    let exponent = (attempts.max(1) - 1).min(31) as u32;
+    options
+        .retry_base
+        .checked_mul(1 << exponent)
+        .map_or(options.retry_max, |delay| delay.min(options.retry_max))
+}
+
+/// 发送一次，2xx算成功。返回对方的状态码和失败原因
+async fn send(client: &awc::Client, target: &Webhook, delivery: &WebhookDelivery) -> (Option<i32>, Option<String>) {
+    let timestamp = Utc::now().timestamp();
+    let request = client
+        .post(target.url.as_str())
+        .content_type("application/json")
+        .header("X-Webhook-Event", delivery.event.as_str())
+        .header("X-Webhook-Delivery", delivery.id.to_string())
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", format!("sha256={}", sign(&target.secret, timestamp, &delivery.payload)));
+    match request.send_body(delivery.payload.clone()).await {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16() as i32), None),
+        Ok(response) => (Some(response.status().as_u16() as i32), Some(format!("Unexpected status {}", response.status()))),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+/// 记下一次尝试的结果：成功、安排下次重试，或者次数用完后放弃
+fn record_attempt(
+    db_connection: &SqliteConnection,
+    options: &WebhookOptions,
+    delivery: &WebhookDelivery,
+    response_status: Option<i32>,
+    error: Option<String>,
+) -> QueryResult<usize> {
+    use crate::schema::webhook_delivery::dsl::*;
+    let now = Utc::now().naive_utc();
+    let tried = delivery.attempts + 1;
+    let (new_status, retry_at, finished_at) = match &error {
+        None => (DELIVERED, now, Some(now)),
+        Some(_) if tried >= options.max_attempts => (FAILED, now, None),
+        Some(_) => (PENDING, now + chrono::Duration::from_std(retry_delay(options, tried)).unwrap_or_else(|_| chrono::Duration::hours(1)), None),
+    };
+    diesel::update(webhook_delivery.find(delivery.id))
+        .set((
+            status.eq(new_status),
+            attempts.eq(tried),
+            next_attempt_at.eq(retry_at),
+            last_status.eq(response_status),
+            last_error.eq(error),
+            delivered_at.eq(finished_at),
+        ))
+        .execute(db_connection)
+}
+
+/// 投递已经到期的事件，返回尝试了多少条。同一个webhook的多条投递也是并发发送的，
+/// 所以接收方不能依赖到达顺序，要按载荷里的内容处理
+pub async fn deliver_due(pool: &Pool, options: &WebhookOptions) -> Result<usize, String> {
+    let loader = pool.clone();
+    let due = web::block(move || {
+        use crate::schema::{webhook, webhook_delivery};
+        let db_connection = loader.get().map_err(|e| e.to_string())?;
+        webhook_delivery::table
+            .inner_join(webhook::table)
+            .filter(webhook_delivery::status.eq(PENDING))
+            .filter(webhook_delivery::next_attempt_at.le(Utc::now().naive_utc()))
+            .order((webhook_delivery::next_attempt_at, webhook_delivery::id))
+            .limit(DELIVERY_BATCH)
+            .load::<(WebhookDelivery, Webhook)>(&db_connection)
+            .map_err(|e| e.to_string())
+    }).await.map_err(|e| e.to_string())?;
+    if due.is_empty() {
+        return Ok(0);
+    }
+    let client = awc::Client::builder().timeout(options.timeout).finish();
+    let outcomes = future::join_all(due.iter().map(|(delivery, target)| send(&client, target, delivery))).await;
+    let attempted = due.len();
+    let pool = pool.clone();
+    let options = options.clone();
+    web::block(move || {
+        let db_connection = pool.get().map_err(|e| e.to_string())?;
+        for ((delivery, target), (response_status, error)) in due.into_iter().zip(outcomes) {
+            match &error {
+                None => tracing::debug!(delivery = delivery.id, webhook = target.id, "webhook delivered"),
+                Some(e) => tracing::warn!(delivery = delivery.id, webhook = target.id, attempts = delivery.attempts + 1, error = %e, "webhook delivery failed"),
+            }
+            record_attempt(&db_connection, &options, &delivery, response_status, error).map_err(|e| e.to_string())?;
+        }
+        Ok::<_, String>(())
+    }).await.map_err(|e| e.to_string())?;
+    Ok(attempted)
+}
+
+/// 在actix运行时里每隔`poll_interval`投递一次到期的事件，一轮投满时不等待直接继续
+pub fn spawn_webhook_worker(jobs: &mut Jobs, pool: Pool, options: WebhookOptions) {
+    let period = options.poll_interval;
+    jobs.spawn("webhooks", rt::time::Instant::now(), period, move || {
+        let pool = pool.clone();
+        let options = options.clone();
+        async move {
+            loop {
+                match deliver_due(&pool, &options).await {
+                    Ok(attempted) if attempted as i64 == DELIVERY_BATCH => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        tracing::error!(error = %e, "webhook worker failed");
+                        break;
+                    },
+                }
+            }
+        }
+    });
+}
+
+fn random_secret() -> Result<String, ApiError> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| ApiError::Unavailable(String::from("Unable to generate a webhook secret")))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn validate(received: &ReceiveWebhookJson) -> Result<Vec<&str>, ApiError> {
+    let valid_url = match received.url.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some(),
+        Err(_) => false,
+    };
+    if !valid_url {
+        return Err(ApiError::invalid_field("url", "Field 'url' must be an absolute http or https URL"));
+    }
+    if matches!(&received.secret, Some(secret) if secret.is_empty()) {
+        return Err(ApiError::invalid_field("secret", "Field 'secret' must not be empty"));
+    }
+    let mut events = Vec::new();
+    for name in &received.events {
+        match EVENTS.iter().find(|known| *known == name) {
+            Some(known) if !events.contains(known) => events.push(*known),
+            Some(_) => {},
+            None => return Err(ApiError::invalid_field("events", format!("Unknown event '{}'", name))),
+        }
+    }
+    if events.is_empty() {
+        return Err(ApiError::invalid_field("events", "Field 'events' must not be empty"));
+    }
+    Ok(events)
+}
+
+/// 登记一个webhook，响应里带有签名用的`secret`
+#[post("/api/admin/webhooks")]
+pub async fn create_webhook(request_raw: Result<Bytes, Error>, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    use crate::schema::webhook::dsl::*;
+    let request_raw = request_raw.map_err(ApiError::from_payload)?;
+    let received: ReceiveWebhookJson = serde_json::from_slice(&request_raw).map_err(|e| ApiError::InvalidBody(e.to_string()))?;
+    let names = validate(&received)?.join(",");
+    let new_secret = match &received.secret {
+        Some(given) => given.clone(),
+        None => random_secret()?,
+    };
+    let db_connection = pool.get()?;
+    let saved = db_connection.immediate_transaction(|| {
+        insert_into(webhook)
+            .values((
+                url.eq(&received.url),
+                secret.eq(&new_secret),
+                events.eq(&names),
+                created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&db_connection)?;
+        webhook.order(id.desc()).first::<Webhook>(&db_connection)
+    })?;
+    tracing::info!(webhook = saved.id, url = %saved.url, events = %saved.events, "webhook registered");
+    let mut body = WebhookJson::from(saved);
+    body.secret = Some(new_secret);
+    Ok(HttpResponse::Created().json(body))
+}
+
+#[get("/api/admin/webhooks")]
+pub async fn list_webhooks(pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    use crate::schema::webhook::dsl::*;
+    let db_connection = pool.get()?;
+    let found = webhook.order(id).load::<Webhook>(&db_connection)?;
+    Ok(HttpResponse::Ok().json(found.into_iter().map(WebhookJson::from).collect::<Vec<_>>()))
+}
+
+/// 删除webhook和它的投递记录，还没投递的事件也不再投递
+#[delete("/api/admin/webhooks/{id}")]
+pub async fn delete_webhook(path: web::Path<i32>, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    use crate::schema::{webhook, webhook_delivery};
+    let target = path.into_inner();
+    let db_connection = pool.get()?;
+    let deleted = db_connection.transaction(|| {
+        diesel::delete(webhook_delivery::table.filter(webhook_delivery::webhook.eq(target))).execute(&db_connection)?;
+        diesel::delete(webhook::table.find(target)).execute(&db_connection)
+    })?;
+    if deleted == 0 {
+        return Err(ApiError::NotFound(format!("Webhook {} does not exist", target)));
+    }
+    tracing::info!(webhook = target, "webhook deleted");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// 投递记录，从新到旧。`?status=`只看某种状态，`?limit=`默认50，最多500
+#[get("/api/admin/webhooks/{id}/deliveries")]
+pub async fn list_deliveries(path: web::Path<i32>, request: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse, ApiError> {
+    use crate::schema::{webhook, webhook_delivery};
+    let target = path.into_inner();
+    let query_string = QString::from(request.query_string());
+    let limit = match query_string.get("limit") {
+        Some(text) => match text.parse::<i64>() {
+            Ok(limit) if (1..=MAX_LOG_LIMIT).contains(&limit) => limit,
+            _ => return Err(ApiError::invalid_field("limit", format!("limit must be between 1 and {}", MAX_LOG_LIMIT))),
+        },
+        None => DEFAULT_LOG_LIMIT,
+    };
+    let status = match query_string.get("status") {
+        Some(status) if [PENDING, DELIVERED, FAILED].contains(&status) => Some(status),
+        Some(status) => return Err(ApiError::invalid_field("status", format!("Unknown status '{}'", status))),
+        None => None,
+    };
+    let db_connection = pool.get()?;
+    if webhook::table.find(target).select(webhook::id).first::<i32>(&db_connection).optional()?.is_none() {
+        return Err(ApiError::NotFound(format!("Webhook {} does not exist", target)));
+    }
+    let mut query = webhook_delivery::table
+        .filter(webhook_delivery::webhook.eq(target))
+        .order(webhook_delivery::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(status) = status {
+        query = query.filter(webhook_delivery::status.eq(status));
+    }
+    let deliveries = query.load::<WebhookDelivery>(&db_connection)?;
+    Ok(HttpResponse::Ok().json(deliveries.into_iter().map(DeliveryJson::from).collect::<Vec<_>>()))
+}
@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message, ProtocolError};
+use actix_web::{Error, HttpRequest, HttpResponse, ResponseError, rt, web::{self, Bytes, BytesMut}};
+use futures::{SinkExt, Stream, StreamExt, channel::mpsc, stream};
+use qstring::QString;
+use crate::Pool;
+use crate::board;
+use crate::config::ApiConfig;
+use crate::error::ApiError;
+use crate::events::{self, BoardEvent, EventFilter, HUB};
+
+/// 发往客户端的帧在这里排队，写不出去时会话停下来等待，事件就积压在订阅的缓冲区里
+const OUTGOING_FRAMES: usize = 16;
+
+enum Input {
+    Frame(Result<Frame, ProtocolError>),
+    Disconnected,
+    Event(Arc<BoardEvent>),
+    Lagged,
+    Heartbeat,
+}
+
+/// `?user=`可以出现多次，只接收这些用户的事件
+fn parse_filter(request: &HttpRequest, pool: &Pool) -> Result<EventFilter, ApiError> {
+    let names: Vec<String> = QString::from(request.query_string())
+        .into_pairs()
+        .into_iter()
+        .filter(|(key, _)| key == "user")
+        .map(|(_, name)| name)
+        .collect();
+    if names.is_empty() {
+        return Ok(EventFilter::default());
+    }
+    let db_connection = pool.get()?;
+    let mut users = Vec::with_capacity(names.len());
+    for name in &names {
+        let found = board::find_user(&db_connection, name)?
+            .ok_or_else(|| ApiError::NotFound(format!("User '{}' does not exist", name)))?;
+        users.push(found.id);
+    }
+    Ok(EventFilter { users: Some(users) })
+}
+
+/// 把请求体解码成WebSocket帧
+fn frames(payload: web::Payload) -> impl Stream<Item = Result<Frame, ProtocolError>> {
+    stream::unfold(Some((payload, BytesMut::new(), Codec::new())), |state| async move {
+        let (mut payload, mut buffer, mut codec) = state?;
+        loop {
+            match codec.decode(&mut buffer) {
+                Ok(Some(frame)) => return Some((Ok(frame), Some((payload, buffer, codec)))),
+                Ok(None) => match payload.next().await {
+                    Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                    _ => return None,
+                },
+                Err(e) => return Some((Err(e), None)), //协议错误之后不再继续解码
+            }
+        }
+    })
+}
+
+struct Session {
+    codec: Codec,
+    outgoing: mpsc::Sender<Result<Bytes, Error>>,
+}
+
+impl Session {
+    /// 客户端已经断开时返回`false`
+    async fn send(&mut self, message: Message) -> bool {
+        let mut buffer = BytesMut::new();
+        if self.codec.encode(message, &mut buffer).is_err() {
+            return false;
+        }
+        self.outgoing.send(Ok(buffer.freeze())).await.is_ok()
+    }
+
+    async fn close(&mut self, code: CloseCode, description: &str) {
+        self.send(Message::Close(Some(CloseReason::from((code, description))))).await;
+    }
+
+    async fn run(mut self, inputs: impl Stream<Item = Input> + Unpin, client_timeout: Duration) {
+        let mut inputs = inputs;
+        let mut last_seen = Instant::now();
+        while let Some(input) = inputs.next().await {
+            let open = match input {
+                Input::Frame(Ok(Frame::Ping(data))) => {
+                    last_seen = Instant::now();
+                    self.send(Message::Pong(data)).await
+                },
+                Input::Frame(Ok(Frame::Close(reason))) => {
+                    self.send(Message::Close(reason)).await;
+                    false
+                },
+                Input::Frame(Ok(_)) => {
+                    last_seen = Instant::now();
+                    true
+                }, //Pong和客户端发来的其它消息只用来确认连接还活着
+                Input::Frame(Err(e)) => {
+                    self.close(CloseCode::Protocol, &e.to_string()).await;
+                    false
+                },
+                Input::Disconnected => false,
+                Input::Event(event) => match serde_json::to_string(&*event) {
+                    Ok(text) => self.send(Message::Text(text)).await,
+                    Err(_) => true,
+                },
+                Input::Lagged => {
+                    self.close(CloseCode::Policy, "Too many undelivered events").await;
+                    false
+                },
+                Input::Heartbeat if last_seen.elapsed() > client_timeout => {
+                    self.close(CloseCode::Away, "Heartbeat timeout").await;
+                    false
+                },
+                Input::Heartbeat => self.send(Message::Ping(Bytes::new())).await,
+            };
+            if !open {
+                break;
+            }
+        }
+    } //返回后`outgoing`被丢弃，响应体随之结束，连接关闭
+}
+
+/// 建立WebSocket连接，之后每条留言的新增、删除和清空都以JSON文本推送给客户端
+pub async fn connect(
+    request: HttpRequest,
+    payload: web::Payload,
+    pool: web::Data<Pool>,
+    api: web::Data<ApiConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let filter = parse_filter(&request, &pool)?;
+    let mut response = match ws::handshake(request.head()) {
+        Ok(response) => response,
+        Err(e) => return Ok(e.error_response()),
+    };
+    let subscription = HUB.subscribe(filter, api.event_buffer);
+    let inputs = stream::select(
+        stream::select(
+            frames(payload).map(Input::Frame).chain(stream::once(async { Input::Disconnected })),
+            subscription.map(Input::Event).chain(stream::once(async { Input::Lagged })), //订阅只会因为积压太多而结束
+        ),
+        events::heartbeat(Duration::from_secs(api.heartbeat_secs)).map(|_| Input::Heartbeat),
+    );
+    let (outgoing, body) = mpsc::channel(OUTGOING_FRAMES);
+    let session = Session { codec: Codec::new(), outgoing };
+    rt::spawn(session.run(Box::pin(inputs), Duration::from_secs(api.client_timeout_secs)));
+    Ok(response.streaming(body))
+}